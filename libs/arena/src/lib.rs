@@ -0,0 +1,239 @@
+//! A bump allocator over a caller provided memory region.
+//!
+//! Allocating only moves a pointer forward. Memory is given back all at once with `reset`, or
+//! back to an earlier state with `checkpoint` and `rollback`. This makes arenas useful as scratch
+//! space, e.g. during boot or for a single request.
+
+#![feature(const_fn)]
+#![no_std]
+
+/// A bump allocator for the memory `[start, end)`.
+#[derive(Debug)]
+pub struct Arena {
+    start: usize,
+    end: usize,
+    next: usize,
+    /// The start of the most recent allocation that can still be reclaimed.
+    last: Option<usize>,
+    reclaim_last: bool,
+}
+
+/// A saved state of an arena, see `Arena::checkpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    next: usize,
+}
+
+impl Arena {
+    /// Creates an arena that allocates from `[start, start + size)`. The memory must be valid
+    /// and unused for as long as allocations from the arena are used.
+    pub const fn new(start: usize, size: usize) -> Arena {
+        Arena {
+            start: start,
+            end: start + size,
+            next: start,
+            last: None,
+            reclaim_last: false,
+        }
+    }
+
+    /// Creates an arena like `new` that reclaims the most recent allocation when it is
+    /// deallocated, see `set_reclaim_last`.
+    pub const fn with_reclaim_last(start: usize, size: usize) -> Arena {
+        Arena {
+            start: start,
+            end: start + size,
+            next: start,
+            last: None,
+            reclaim_last: true,
+        }
+    }
+
+    /// If enabled, deallocating the most recent allocation gives its memory back to the arena.
+    /// All other deallocations are ignored.
+    pub fn set_reclaim_last(&mut self, reclaim_last: bool) {
+        self.reclaim_last = reclaim_last;
+    }
+
+    /// Allocates `size` bytes with the given alignment. Returns `None` if the arena is full.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let alloc_start = align_up(self.next, align);
+        let alloc_end = match alloc_start.checked_add(size) {
+            Some(end) => end,
+            None => return None,
+        };
+
+        if alloc_end <= self.end {
+            self.next = alloc_end;
+            self.last = Some(alloc_start);
+            Some(alloc_start as *mut u8)
+        } else {
+            None
+        }
+    }
+
+    /// Deallocates the `size` bytes at `ptr`. The memory is only reclaimed if reclaiming is
+    /// enabled and it was the most recent allocation. Returns true if it was reclaimed.
+    pub fn deallocate(&mut self, ptr: *mut u8, size: usize) -> bool {
+        if self.reclaim_last && self.is_last(ptr, size) {
+            self.next = ptr as usize;
+            self.last = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if the `size` bytes at `ptr` are the most recent allocation, which can be
+    /// resized in place.
+    pub fn is_last(&self, ptr: *mut u8, size: usize) -> bool {
+        let ptr = ptr as usize;
+        self.last == Some(ptr) && ptr + size == self.next
+    }
+
+    /// Resizes the most recent allocation of `size` bytes at `ptr` to `new_size` bytes without
+    /// moving it. Returns false if it isn't the most recent allocation or the arena is too small.
+    pub fn resize_last(&mut self, ptr: *mut u8, size: usize, new_size: usize) -> bool {
+        if !self.is_last(ptr, size) {
+            return false;
+        }
+        match (ptr as usize).checked_add(new_size) {
+            Some(new_end) if new_end <= self.end => {
+                self.next = new_end;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the current state, which `rollback` returns to.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { next: self.next }
+    }
+
+    /// Frees everything that was allocated after `checkpoint` was taken.
+    ///
+    /// Unsafe because the freed allocations must no longer be used.
+    pub unsafe fn rollback(&mut self, checkpoint: Checkpoint) {
+        assert!(checkpoint.next >= self.start && checkpoint.next <= self.next,
+                "checkpoint does not belong to this arena or was already rolled back");
+        self.next = checkpoint.next;
+        self.last = None;
+    }
+
+    /// Frees all allocations.
+    ///
+    /// Unsafe because the freed allocations must no longer be used.
+    pub unsafe fn reset(&mut self) {
+        self.next = self.start;
+        self.last = None;
+    }
+
+    /// Adds the `size` bytes behind the end of the arena, which must be valid and unused memory.
+    pub fn extend(&mut self, size: usize) {
+        self.end += size;
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Returns the size of the arena in bytes.
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the number of allocated bytes, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next - self.start
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.end - self.next
+    }
+}
+
+/// Align downwards. Returns the greatest x with alignment `align`
+/// so that x <= addr. The alignment must be a power of 2.
+pub fn align_down(addr: usize, align: usize) -> usize {
+    if align.is_power_of_two() {
+        addr & !(align - 1)
+    } else if align == 0 {
+        addr
+    } else {
+        panic!("`align` must be a power of 2");
+    }
+}
+
+/// Align upwards. Returns the smallest x with alignment `align`
+/// so that x >= addr. The alignment must be a power of 2.
+pub fn align_up(addr: usize, align: usize) -> usize {
+    align_down(addr + align - 1, align)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocations_are_aligned_and_bounded() {
+        let mut arena = Arena::new(0x1000, 0x100);
+        assert_eq!(arena.allocate(3, 1), Some(0x1000 as *mut u8));
+        assert_eq!(arena.allocate(8, 8), Some(0x1008 as *mut u8));
+        assert_eq!(arena.used(), 0x10);
+        assert_eq!(arena.allocate(0xf1, 1), None);
+        assert_eq!(arena.allocate(0xf0, 1), Some(0x1010 as *mut u8));
+        assert_eq!(arena.remaining(), 0);
+    }
+
+    #[test]
+    fn rollback_frees_later_allocations() {
+        let mut arena = Arena::new(0x1000, 0x100);
+        arena.allocate(0x10, 1).unwrap();
+        let checkpoint = arena.checkpoint();
+        arena.allocate(0x20, 1).unwrap();
+        arena.allocate(0x30, 1).unwrap();
+        unsafe { arena.rollback(checkpoint) };
+        assert_eq!(arena.used(), 0x10);
+        assert_eq!(arena.allocate(1, 1), Some(0x1010 as *mut u8));
+        unsafe { arena.reset() };
+        assert_eq!(arena.used(), 0);
+    }
+
+    #[test]
+    fn only_the_last_allocation_is_reclaimed() {
+        let mut arena = Arena::new(0x1000, 0x100);
+        let a = arena.allocate(0x10, 1).unwrap();
+        let b = arena.allocate(0x10, 1).unwrap();
+        assert!(!arena.deallocate(b, 0x10));
+
+        arena.set_reclaim_last(true);
+        assert!(!arena.deallocate(a, 0x10));
+        assert!(arena.deallocate(b, 0x10));
+        assert_eq!(arena.used(), 0x10);
+        // the allocation before the reclaimed one is unknown
+        assert!(!arena.deallocate(a, 0x10));
+    }
+
+    #[test]
+    fn the_last_allocation_is_resized_in_place() {
+        let mut arena = Arena::new(0x1000, 0x100);
+        let a = arena.allocate(0x10, 1).unwrap();
+        let b = arena.allocate(0x10, 1).unwrap();
+        assert!(!arena.resize_last(a, 0x10, 0x20));
+        assert!(arena.resize_last(b, 0x10, 0x40));
+        assert_eq!(arena.used(), 0x50);
+        assert!(arena.resize_last(b, 0x40, 0x8));
+        assert_eq!(arena.used(), 0x18);
+        assert!(!arena.resize_last(b, 0x8, 0x100));
+        assert_eq!(arena.used(), 0x18);
+    }
+
+    #[test]
+    fn extend_grows_the_arena() {
+        let mut arena = Arena::new(0x1000, 0x10);
+        assert_eq!(arena.allocate(0x20, 1), None);
+        arena.extend(0x10);
+        assert_eq!(arena.allocate(0x20, 1), Some(0x1000 as *mut u8));
+    }
+}
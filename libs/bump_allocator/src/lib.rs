@@ -5,75 +5,62 @@
 #![no_std]
 
 use spin::Mutex;
+use arena::Arena;
 
 extern crate spin;
-
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
-
-static BUMP_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(
-    BumpAllocator::new(HEAP_START, HEAP_SIZE));
-
-#[derive(Debug)]
-struct BumpAllocator {
-    heap_start: usize,
-    heap_size: usize,
-    next: usize,
+extern crate arena;
+extern crate heap_stats;
+extern crate growable_heap;
+
+pub use heap_stats::{Stats, Allocation, LeakCheck, stats, start_leak_check, stop_leak_check,
+                     for_each_leak};
+pub use growable_heap::{HEAP_START, HEAP_SIZE, HEAP_WINDOW_SIZE, set_grow_handler, set_limit};
+pub use arena::{Arena, Checkpoint, align_down, align_up};
+
+/// The global heap. Freeing the most recent allocation gives its memory back, so a temporary
+/// buffer that is freed before the next allocation doesn't leak. The most recent allocation is
+/// also resized in place, so a growing `Vec` at the end of the heap doesn't leak either.
+static BUMP_ALLOCATOR: Mutex<Arena> = Mutex::new(Arena::with_reclaim_last(HEAP_START, HEAP_SIZE));
+
+/// Returns the current size of the heap in bytes.
+pub fn size() -> usize {
+    BUMP_ALLOCATOR.lock().size()
 }
 
-impl BumpAllocator {
-    /// Create a new allocator, which uses the memory in the
-    /// range [heap_start, heap_start + heap_size).
-    const fn new(heap_start: usize, heap_size: usize) -> BumpAllocator {
-        BumpAllocator {
-            heap_start: heap_start,
-            heap_size: heap_size,
-            next: heap_start,
-        }
-    }
-
-    /// Allocates a block of memory with the given size and alignment.
-    fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        let alloc_start = align_up(self.next, align);
-        let alloc_end = alloc_start + size;
-
-        if alloc_end <= self.heap_start + self.heap_size {
-            self.next = alloc_end;
-            Some(alloc_start as *mut u8)
-        } else {
-            None
+/// Grows the heap so that an allocation of `size` bytes with the given alignment fits.
+/// Returns false if the limit is reached or there is no memory left.
+fn grow(arena: &mut Arena, size: usize, align: usize) -> bool {
+    match growable_heap::grow(arena.start() + arena.size(), size, align) {
+        Some(grown) => {
+            arena.extend(grown);
+            true
         }
+        None => false,
     }
 }
 
-/// Align downwards. Returns the greatest x with alignment `align`
-/// so that x <= addr. The alignment must be a power of 2.
-pub fn align_down(addr: usize, align: usize) -> usize {
-    if align.is_power_of_two() {
-        addr & !(align - 1)
-    } else if align == 0 {
-        addr
-    } else {
-        panic!("`align` must be a power of 2");
-    }
-}
-
-/// Align upwards. Returns the smallest x with alignment `align`
-/// so that x >= addr. The alignment must be a power of 2.
-pub fn align_up(addr: usize, align: usize) -> usize {
-    align_down(addr + align - 1, align)
-}
-
 #[no_mangle]
 pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    BUMP_ALLOCATOR.lock().allocate(size, align).expect("out of memory")
+    let mut arena = BUMP_ALLOCATOR.lock();
+    loop {
+        if let Some(ptr) = arena.allocate(size, align) {
+            heap_stats::record_allocation(ptr, size);
+            return ptr;
+        }
+        if !grow(&mut arena, size, align) {
+            heap_stats::record_failure();
+            panic!("out of memory");
+        }
+    }
 }
 
 #[no_mangle]
-pub extern fn __rust_deallocate(_ptr: *mut u8, _size: usize,
+pub extern fn __rust_deallocate(ptr: *mut u8, size: usize,
     _align: usize)
 {
-    // just leak it
+    // only the most recent allocation is reclaimed, everything else is leaked
+    BUMP_ALLOCATOR.lock().deallocate(ptr, size);
+    heap_stats::record_deallocation(ptr, size);
 }
 
 #[no_mangle]
@@ -81,11 +68,28 @@ pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
     size
 }
 
+/// Resizes `ptr` without moving it if it is the most recent allocation, growing the heap if
+/// needed. Returns false if it has to be moved.
+fn resize_in_place(ptr: *mut u8, size: usize, new_size: usize) -> bool {
+    let mut arena = BUMP_ALLOCATOR.lock();
+    loop {
+        if arena.resize_last(ptr, size, new_size) {
+            heap_stats::record_deallocation(ptr, size);
+            heap_stats::record_allocation(ptr, new_size);
+            return true;
+        }
+        let missing = (ptr as usize + new_size).saturating_sub(arena.start() + arena.size());
+        if !arena.is_last(ptr, size) || missing == 0 || !grow(&mut arena, missing, 1) {
+            return false;
+        }
+    }
+}
+
 #[no_mangle]
-pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
-    _new_size: usize, _align: usize) -> usize
+pub extern fn __rust_reallocate_inplace(ptr: *mut u8, size: usize,
+    new_size: usize, _align: usize) -> usize
 {
-    size
+    if resize_in_place(ptr, size, new_size) { new_size } else { size }
 }
 
 #[no_mangle]
@@ -93,6 +97,10 @@ pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
                                 align: usize) -> *mut u8 {
     use core::{ptr, cmp};
 
+    if resize_in_place(ptr, size, new_size) {
+        return ptr;
+    }
+
     // from: https://github.com/rust-lang/rust/blob/
     //     c66d2380a810c9a2b3dbb4f93a830b101ee49cc2/
     //     src/liballoc_system/lib.rs#L98-L101
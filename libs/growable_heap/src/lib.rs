@@ -0,0 +1,118 @@
+//! The heap window and its growth, shared by the heap allocators.
+//!
+//! The heap starts with `HEAP_SIZE` bytes at `HEAP_START`. When an allocator runs out of
+//! memory, `grow` lets the kernel map more memory behind the end of the heap through the handler
+//! set by `set_grow_handler`, up to the limit set by `set_limit`.
+//!
+//! `GrowableHeap` is a hole list heap that grows this way. The hole list allocator uses it
+//! directly, the slab allocator takes its slabs and large allocations from it.
+
+#![feature(const_fn)]
+#![no_std]
+
+use spin::Mutex;
+use linked_list_allocator::Heap;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+extern crate spin;
+extern crate linked_list_allocator;
+
+pub const HEAP_START: usize = 0o_177777_775_000_000_000_0000; // P4 entry 509
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, the initial size
+
+/// The size of the virtual window reserved for the heap. The heap never grows beyond it.
+pub const HEAP_WINDOW_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
+
+/// The heap grows by at least this many bytes at once.
+const GROWTH_STEP: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// Called with the start address and the size of the memory that the heap grows into. It must
+/// map the memory and return true, or return false if there are no free frames left.
+static GROW_HANDLER: Mutex<Option<fn(usize, usize) -> bool>> = Mutex::new(None);
+
+/// The maximum size of the heap in bytes.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_WINDOW_SIZE);
+
+/// Sets the function that maps more memory when the heap is full. Without it, the heap has a
+/// fixed size of `HEAP_SIZE`.
+pub fn set_grow_handler(handler: fn(usize, usize) -> bool) {
+    *GROW_HANDLER.lock() = Some(handler);
+}
+
+/// Sets the maximum size of the heap. It can't be larger than `HEAP_WINDOW_SIZE`.
+pub fn set_limit(limit: usize) {
+    assert!(limit <= HEAP_WINDOW_SIZE, "heap limit is larger than the heap window");
+    HEAP_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Maps memory at `end`, the current end of the heap, so that an allocation of `size` bytes
+/// with the given alignment fits into it. Returns the number of mapped bytes, or `None` if the
+/// limit is reached or there is no memory left.
+pub fn grow(end: usize, size: usize, align: usize) -> Option<usize> {
+    let grow_handler = match *GROW_HANDLER.lock() {
+        Some(grow_handler) => grow_handler,
+        None => return None,
+    };
+
+    // huge requests must not overflow, they fail at the limit check instead
+    let size = match size.checked_add(align).and_then(|size| size.checked_add(PAGE_SIZE - 1)) {
+        Some(size) => cmp::max(size, GROWTH_STEP) & !(PAGE_SIZE - 1),
+        None => return None,
+    };
+    match end.checked_add(size) {
+        Some(new_end) if new_end <= HEAP_START + HEAP_LIMIT.load(Ordering::SeqCst) => {}
+        _ => return None,
+    }
+    if !grow_handler(end, size) {
+        return None;
+    }
+    Some(size)
+}
+
+/// A hole list heap that grows through `grow` when no hole fits an allocation.
+pub struct GrowableHeap {
+    heap: Heap,
+    /// The end of the memory that belongs to the heap.
+    end: usize,
+}
+
+impl GrowableHeap {
+    /// Creates the heap with the initial `HEAP_SIZE` bytes at `HEAP_START`.
+    ///
+    /// Unsafe because the initial memory must be mapped and must not be used otherwise.
+    pub unsafe fn new() -> GrowableHeap {
+        GrowableHeap {
+            heap: Heap::new(HEAP_START, HEAP_SIZE),
+            end: HEAP_START + HEAP_SIZE,
+        }
+    }
+
+    /// Returns the current size of the heap in bytes.
+    pub fn size(&self) -> usize {
+        self.end - HEAP_START
+    }
+
+    /// Allocates from the hole list and grows the heap if there is no fitting hole. Returns
+    /// `None` if the heap can't grow anymore.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        loop {
+            if let Some(ptr) = self.heap.allocate_first_fit(size, align) {
+                return Some(ptr);
+            }
+            let grown = match grow(self.end, size, align) {
+                Some(grown) => grown,
+                None => return None,
+            };
+            // freeing the new memory adds it to the hole list, where it merges with a free
+            // block at the end of the heap
+            unsafe { self.heap.deallocate(self.end as *mut u8, grown, 1) };
+            self.end += grown;
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize) {
+        self.heap.deallocate(ptr, size, align)
+    }
+}
@@ -0,0 +1,232 @@
+//! Statistics and leak tracking shared by the heap allocators.
+//!
+//! The allocators report every allocation and deallocation here. The kernel queries the numbers
+//! through the allocator crate, which re-exports this API.
+
+#![feature(const_fn)]
+#![no_std]
+
+use spin::Mutex;
+use core::fmt;
+
+extern crate spin;
+
+/// The number of size buckets: up to 8, 16, ..., 4096 bytes and larger.
+pub const BUCKET_COUNT: usize = 11;
+
+/// The maximum number of outstanding allocations that a leak check can record.
+pub const MAX_TRACKED: usize = 256;
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Bytes that are currently allocated.
+    pub live_bytes: usize,
+    /// The highest value of `live_bytes` so far.
+    pub peak_bytes: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    /// Allocations that failed because the heap was full.
+    pub failed_allocations: usize,
+    /// The number of allocations per size bucket, see `bucket_size`.
+    pub buckets: [usize; BUCKET_COUNT],
+}
+
+impl Stats {
+    const fn new() -> Stats {
+        Stats {
+            live_bytes: 0,
+            peak_bytes: 0,
+            allocations: 0,
+            deallocations: 0,
+            failed_allocations: 0,
+            buckets: [0; BUCKET_COUNT],
+        }
+    }
+
+    /// Returns the largest allocation size of the given bucket. The last bucket has no limit.
+    pub fn bucket_size(index: usize) -> Option<usize> {
+        if index + 1 < BUCKET_COUNT {
+            Some(8 << index)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f,
+                    "heap: {} bytes live, {} bytes peak, {} allocations, {} deallocations, {} \
+                     failed\n",
+                    self.live_bytes,
+                    self.peak_bytes,
+                    self.allocations,
+                    self.deallocations,
+                    self.failed_allocations));
+        try!(write!(f, "heap: allocations by size:"));
+        for (index, &count) in self.buckets.iter().enumerate() {
+            match Stats::bucket_size(index) {
+                Some(size) => try!(write!(f, " <={}: {}", size, count)),
+                None => try!(write!(f, " larger: {}", count)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    let size = if size < 8 { 8 } else { size.next_power_of_two() };
+    let index = (size.trailing_zeros() - 3) as usize;
+    if index < BUCKET_COUNT { index } else { BUCKET_COUNT - 1 }
+}
+
+/// An allocation that was recorded by a leak check.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    pub tag: &'static str,
+    /// Allocations are numbered in the order they were made.
+    sequence: usize,
+}
+
+/// Marks the start of a leak check, see `start_leak_check`.
+#[derive(Debug, Clone, Copy)]
+pub struct LeakCheck {
+    sequence: usize,
+}
+
+struct Tracker {
+    stats: Stats,
+    /// Allocations are only recorded while `tag` is set.
+    tag: Option<&'static str>,
+    outstanding: [Option<Allocation>; MAX_TRACKED],
+    /// The sequence number of the next allocation.
+    sequence: usize,
+    /// The number of allocations that weren't recorded because the table was full.
+    untracked: usize,
+}
+
+impl Tracker {
+    const fn new() -> Tracker {
+        Tracker {
+            stats: Stats::new(),
+            tag: None,
+            outstanding: [None; MAX_TRACKED],
+            sequence: 0,
+            untracked: 0,
+        }
+    }
+}
+
+/// Records a successful allocation of `size` bytes at `ptr`.
+pub fn record_allocation(ptr: *mut u8, size: usize) {
+    let mut tracker = TRACKER.lock();
+    let tracker = &mut *tracker;
+
+    tracker.stats.live_bytes += size;
+    if tracker.stats.live_bytes > tracker.stats.peak_bytes {
+        tracker.stats.peak_bytes = tracker.stats.live_bytes;
+    }
+    tracker.stats.allocations += 1;
+    tracker.stats.buckets[bucket(size)] += 1;
+
+    if let Some(tag) = tracker.tag {
+        let allocation = Allocation {
+            ptr: ptr as usize,
+            size: size,
+            tag: tag,
+            sequence: tracker.sequence,
+        };
+        tracker.sequence += 1;
+        match tracker.outstanding.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => *entry = Some(allocation),
+            None => tracker.untracked += 1,
+        }
+    }
+}
+
+/// Records the deallocation of `size` bytes at `ptr`.
+pub fn record_deallocation(ptr: *mut u8, size: usize) {
+    let mut tracker = TRACKER.lock();
+    tracker.stats.live_bytes -= size;
+    tracker.stats.deallocations += 1;
+
+    let ptr = ptr as usize;
+    if let Some(entry) = tracker.outstanding
+                                .iter_mut()
+                                .find(|entry| entry.map_or(false, |a| a.ptr == ptr)) {
+        *entry = None;
+    }
+}
+
+/// Records an allocation that failed because the heap was full.
+pub fn record_failure() {
+    TRACKER.lock().stats.failed_allocations += 1;
+}
+
+/// Returns the current statistics.
+pub fn stats() -> Stats {
+    TRACKER.lock().stats
+}
+
+/// Starts recording the outstanding allocations, marked with `tag`. Calling it again while a
+/// check is running changes the tag of the following allocations.
+pub fn start_leak_check(tag: &'static str) -> LeakCheck {
+    let mut tracker = TRACKER.lock();
+    tracker.tag = Some(tag);
+    LeakCheck { sequence: tracker.sequence }
+}
+
+/// Stops recording allocations. Recorded allocations stay recorded until they are freed.
+pub fn stop_leak_check() {
+    TRACKER.lock().tag = None;
+}
+
+/// Calls `f` for every allocation since `check` that wasn't freed. Returns the number of
+/// allocations that couldn't be recorded because more than `MAX_TRACKED` were outstanding.
+///
+/// `f` must not allocate.
+pub fn for_each_leak<F>(check: LeakCheck, mut f: F) -> usize
+    where F: FnMut(&Allocation)
+{
+    let tracker = TRACKER.lock();
+    for allocation in tracker.outstanding.iter().filter_map(|entry| entry.as_ref()) {
+        if allocation.sequence >= check.sequence {
+            f(allocation);
+        }
+    }
+    tracker.untracked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bucket, Stats, BUCKET_COUNT};
+
+    #[test]
+    fn sizes_are_counted_in_their_power_of_two_bucket() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(8), 0);
+        assert_eq!(bucket(9), 1);
+        assert_eq!(bucket(16), 1);
+        assert_eq!(bucket(4096), BUCKET_COUNT - 2);
+    }
+
+    #[test]
+    fn larger_sizes_are_counted_in_the_last_bucket() {
+        assert_eq!(bucket(4097), BUCKET_COUNT - 1);
+        assert_eq!(bucket(1 << 20), BUCKET_COUNT - 1);
+    }
+
+    #[test]
+    fn bucket_sizes_match_the_buckets() {
+        for index in 0..(BUCKET_COUNT - 1) {
+            let size = Stats::bucket_size(index).unwrap();
+            assert_eq!(bucket(size), index);
+            assert_eq!(bucket(size + 1), index + 1);
+        }
+        assert_eq!(Stats::bucket_size(BUCKET_COUNT - 1), None);
+    }
+}
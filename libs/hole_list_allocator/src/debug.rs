@@ -0,0 +1,222 @@
+//! Heap debugging: every allocation is surrounded by red zones and freed memory is poisoned.
+//!
+//! An allocation of `size` bytes is laid out like this:
+//!
+//! ```text
+//! | padding | Header | front red zone | user data (size) | back red zone |
+//! ^ block start                       ^ returned pointer
+//! ```
+//!
+//! The padding keeps the returned pointer aligned and leaves room for the hole information that
+//! the hole list writes to the start of a freed block, so that the header survives the free and
+//! a second free of the same pointer can be detected. This only works as long as the block isn't
+//! handed out again: if a later allocation reuses the freed block, a second free of the old
+//! pointer either hits the new header and frees the new allocation, or reports an overwritten
+//! header.
+//!
+//! The callers of every allocation are found through the saved frame pointers, so the kernel
+//! must be compiled with `-C force-frame-pointers=yes`, which the Makefile does for the
+//! `heap_debug` feature.
+
+use core::{cmp, fmt, mem, ptr, slice};
+use growable_heap::GrowableHeap;
+use spin::Mutex;
+
+const RED_ZONE_SIZE: usize = 16;
+/// The hole list stores its hole information (size and next pointer) at the start of a free
+/// block.
+const HOLE_INFO_SIZE: usize = 2 * 8;
+
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Freshly allocated memory is filled with this byte to catch reads of uninitialized memory.
+const ALLOCATED_BYTE: u8 = 0xcd;
+/// Freed memory is filled with this byte to catch uses after free.
+const POISON_BYTE: u8 = 0xdd;
+
+const ALLOCATED_MAGIC: usize = 0xa110_ca7e_d0d0_a110;
+const FREED_MAGIC: usize = 0xf4ee_d0d0_f4ee_d0d0;
+
+/// The number of return addresses that are recorded for every allocation.
+const CALLER_FRAMES: usize = 4;
+
+/// Returns the end of the stack that contains the given address, or `None` if it's on no known
+/// stack.
+static STACK_TOP_HANDLER: Mutex<Option<fn(usize) -> Option<usize>>> = Mutex::new(None);
+
+/// Sets the function that finds the end of the current stack. Without it, no callers are
+/// recorded.
+pub fn set_stack_top_handler(handler: fn(usize) -> Option<usize>) {
+    *STACK_TOP_HANDLER.lock() = Some(handler);
+}
+
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize,
+    /// Return addresses of the allocating code, innermost first.
+    callers: [usize; CALLER_FRAMES],
+}
+
+/// The layout of the block that holds an allocation of `size` bytes with the given alignment.
+/// Returns the offset of the user data in the block, the size and the alignment of the block.
+fn block_layout(size: usize, align: usize) -> (usize, usize, usize) {
+    let align = cmp::max(align, mem::align_of::<Header>());
+    let offset = align_up(HOLE_INFO_SIZE + mem::size_of::<Header>() + RED_ZONE_SIZE, align);
+    (offset, offset + size + RED_ZONE_SIZE, align)
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Formats recorded return addresses as hexadecimal numbers.
+struct Callers<'a>(&'a [usize; CALLER_FRAMES]);
+
+impl<'a> fmt::Display for Callers<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "["));
+        for (i, caller) in self.0.iter().filter(|&&caller| caller != 0).enumerate() {
+            if i > 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{:#x}", caller));
+        }
+        write!(f, "]")
+    }
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.offset(-((RED_ZONE_SIZE + mem::size_of::<Header>()) as isize)) as *mut Header
+}
+
+unsafe fn bytes<'a>(start: *mut u8, len: usize) -> &'a mut [u8] {
+    slice::from_raw_parts_mut(start, len)
+}
+
+#[inline(never)]
+pub fn allocate(heap: &mut GrowableHeap, size: usize, align: usize) -> Option<*mut u8> {
+    let (offset, block_size, block_align) = block_layout(size, align);
+    let block = match heap.allocate(block_size, block_align) {
+        Some(block) => block,
+        None => return None,
+    };
+    unsafe {
+        let ptr = block.offset(offset as isize);
+        ptr::write(header(ptr),
+                   Header {
+                       magic: ALLOCATED_MAGIC,
+                       size: size,
+                       align: align,
+                       callers: callers(),
+                   });
+        for byte in bytes(ptr.offset(-(RED_ZONE_SIZE as isize)), RED_ZONE_SIZE).iter_mut() {
+            *byte = RED_ZONE_BYTE;
+        }
+        for byte in bytes(ptr, size).iter_mut() {
+            *byte = ALLOCATED_BYTE;
+        }
+        for byte in bytes(ptr.offset(size as isize), RED_ZONE_SIZE).iter_mut() {
+            *byte = RED_ZONE_BYTE;
+        }
+        Some(ptr)
+    }
+}
+
+pub unsafe fn deallocate(heap: &mut GrowableHeap, ptr: *mut u8, size: usize, align: usize) {
+    let header = &mut *header(ptr);
+    match header.magic {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => {
+            panic!("heap: double free of {:p} ({} bytes, allocated by {})",
+                   ptr,
+                   header.size,
+                   Callers(&header.callers))
+        }
+        magic => {
+            panic!("heap: free of {:p} ({} bytes), which is not an allocation or whose header \
+                    was overwritten (magic {:#x})",
+                   ptr,
+                   size,
+                   magic)
+        }
+    }
+    if header.size != size || header.align != align {
+        panic!("heap: {:p} was allocated with size {} and align {} by {}, but freed with \
+                size {} and align {}",
+               ptr,
+               header.size,
+               header.align,
+               Callers(&header.callers),
+               size,
+               align);
+    }
+    check_red_zone(ptr, header, "front", ptr.offset(-(RED_ZONE_SIZE as isize)));
+    check_red_zone(ptr, header, "back", ptr.offset(size as isize));
+
+    header.magic = FREED_MAGIC;
+    for byte in bytes(ptr, size).iter_mut() {
+        *byte = POISON_BYTE;
+    }
+
+    let (offset, block_size, block_align) = block_layout(size, align);
+    heap.deallocate(ptr.offset(-(offset as isize)), block_size, block_align);
+}
+
+unsafe fn check_red_zone(ptr: *mut u8, header: &Header, name: &str, red_zone: *mut u8) {
+    let red_zone = bytes(red_zone, RED_ZONE_SIZE);
+    if let Some(index) = red_zone.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+        panic!("heap: {} red zone of {:p} ({} bytes, allocated by {}) was overwritten at \
+                byte {}: {:?}",
+               name,
+               ptr,
+               header.size,
+               Callers(&header.callers),
+               index,
+               red_zone);
+    }
+}
+
+/// Returns the innermost return addresses outside of the allocator by following the saved frame
+/// pointers, so it only works if the kernel is compiled with frame pointers. The walk stops at
+/// the end of the current stack, so a bogus frame pointer can't make it read unmapped memory.
+#[inline(always)]
+fn callers() -> [usize; CALLER_FRAMES] {
+    let mut callers = [0; CALLER_FRAMES];
+    let (mut frame, stack_pointer): (usize, usize);
+    unsafe { asm!("mov $0, rbp; mov $1, rsp" : "=r"(frame), "=r"(stack_pointer) ::: "intel") };
+
+    let stack_top = match *STACK_TOP_HANDLER.lock() {
+        Some(stack_top_handler) => stack_top_handler(stack_pointer),
+        None => None,
+    };
+    let stack_top = match stack_top {
+        Some(stack_top) => stack_top,
+        None => return callers,
+    };
+
+    // skip the return address into `__rust_allocate`
+    let mut skip = 1;
+    let mut index = 0;
+    while index < CALLER_FRAMES {
+        // the saved frame pointer and the return address must be on the current stack
+        if frame < stack_pointer || frame > stack_top - 2 * 8 ||
+           frame % mem::align_of::<usize>() != 0 {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            (*(frame as *const usize), *((frame + 8) as *const usize))
+        };
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            callers[index] = return_address;
+            index += 1;
+        }
+        // the stack grows downwards, so the frames of the callers are at higher addresses
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    callers
+}
@@ -1,34 +1,68 @@
 #![feature(allocator)]
 #![feature(const_fn)]
+#![cfg_attr(feature = "debug", feature(asm))]
 
 #![allocator]
 #![no_std]
 
 use spin::Mutex;
-use linked_list_allocator::Heap;
+use growable_heap::GrowableHeap;
 
 extern crate spin;
-extern crate linked_list_allocator;
+extern crate heap_stats;
+extern crate growable_heap;
 #[macro_use]
 extern crate lazy_static;
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+#[cfg(feature = "debug")]
+mod debug;
+
+pub use heap_stats::{Stats, Allocation, LeakCheck, stats, start_leak_check, stop_leak_check,
+                     for_each_leak};
+pub use growable_heap::{HEAP_START, HEAP_SIZE, HEAP_WINDOW_SIZE, set_grow_handler, set_limit};
 
 lazy_static! {
-    static ref HEAP: Mutex<Heap> = Mutex::new(unsafe {
-        Heap::new(HEAP_START, HEAP_SIZE)
-    });
+    static ref HEAP: Mutex<GrowableHeap> = Mutex::new(unsafe { GrowableHeap::new() });
+}
+
+/// Returns the current size of the heap in bytes.
+pub fn size() -> usize {
+    HEAP.lock().size()
+}
+
+#[cfg(not(feature = "debug"))]
+fn allocate(heap: &mut GrowableHeap, size: usize, align: usize) -> Option<*mut u8> {
+    heap.allocate(size, align)
+}
+
+#[cfg(not(feature = "debug"))]
+unsafe fn deallocate(heap: &mut GrowableHeap, ptr: *mut u8, size: usize, align: usize) {
+    heap.deallocate(ptr, size, align)
 }
 
+#[cfg(feature = "debug")]
+use debug::{allocate, deallocate};
+#[cfg(feature = "debug")]
+pub use debug::set_stack_top_handler;
+
 #[no_mangle]
 pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    HEAP.lock().allocate_first_fit(size, align).expect("out of memory")
+    match allocate(&mut HEAP.lock(), size, align) {
+        Some(ptr) => {
+            heap_stats::record_allocation(ptr, size);
+            ptr
+        }
+        None => {
+            heap_stats::record_failure();
+            panic!("out of memory");
+        }
+    }
 }
 
 #[no_mangle]
 pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
-    unsafe { HEAP.lock().deallocate(ptr, size, align) };
+    unsafe { deallocate(&mut HEAP.lock(), ptr, size, align) };
+    heap_stats::record_deallocation(ptr, size);
 }
 
 #[no_mangle]
@@ -0,0 +1,247 @@
+#![feature(allocator)]
+#![feature(const_fn)]
+
+#![allocator]
+#![no_std]
+
+use spin::Mutex;
+use growable_heap::GrowableHeap;
+use core::{cmp, ptr};
+
+extern crate spin;
+extern crate heap_stats;
+extern crate growable_heap;
+#[macro_use]
+extern crate lazy_static;
+
+pub use heap_stats::{Stats, Allocation, LeakCheck, stats, start_leak_check, stop_leak_check,
+                     for_each_leak};
+pub use growable_heap::{HEAP_START, HEAP_SIZE, HEAP_WINDOW_SIZE, set_grow_handler, set_limit};
+
+/// Allocations of up to this size are served from slabs, larger ones from the hole list.
+pub const MAX_OBJECT_SIZE: usize = 2048;
+
+/// The number of size classes: 8, 16, 32, ..., 2048 bytes.
+pub const CACHE_COUNT: usize = 9;
+
+const MIN_OBJECT_SIZE: usize = 8;
+
+const PAGE_SIZE: usize = 4096;
+
+lazy_static! {
+    static ref HEAP: Mutex<SlabHeap> = Mutex::new(SlabHeap {
+        caches: [SlabCache::new(8), SlabCache::new(16), SlabCache::new(32),
+                 SlabCache::new(64), SlabCache::new(128), SlabCache::new(256),
+                 SlabCache::new(512), SlabCache::new(1024), SlabCache::new(2048)],
+        heap: unsafe { GrowableHeap::new() },
+    });
+}
+
+/// Returns the current size of the heap in bytes.
+pub fn size() -> usize {
+    HEAP.lock().heap.size()
+}
+
+/// Statistics of a slab cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    /// The size of the objects in this cache.
+    pub object_size: usize,
+    /// The number of pages that were taken from the hole list for this cache.
+    pub slabs: usize,
+    /// The number of objects that are currently allocated.
+    pub allocated: usize,
+    /// The number of free objects in the slabs.
+    pub free: usize,
+    /// The total number of allocations served by this cache.
+    pub allocations: usize,
+}
+
+/// Returns the statistics of all slab caches, ordered by object size.
+pub fn cache_stats() -> [CacheStats; CACHE_COUNT] {
+    let heap = HEAP.lock();
+    let mut stats = [heap.caches[0].stats; CACHE_COUNT];
+    for (stats, cache) in stats.iter_mut().zip(heap.caches.iter()) {
+        *stats = cache.stats;
+    }
+    stats
+}
+
+/// Returns the index of the cache that serves allocations with the given size and alignment, or
+/// `None` if they are too large for the slabs.
+fn cache_index(size: usize, align: usize) -> Option<usize> {
+    let object_size = cmp::max(cmp::max(size, align), MIN_OBJECT_SIZE).next_power_of_two();
+    if object_size > MAX_OBJECT_SIZE {
+        None
+    } else {
+        Some((object_size.trailing_zeros() - MIN_OBJECT_SIZE.trailing_zeros()) as usize)
+    }
+}
+
+/// A free object in a slab. It stores a pointer to the next free object of the same cache.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Objects of a single size, carved out of page sized slabs.
+///
+/// The slabs are page aligned and the object size is a power of two, so every object is aligned
+/// to its size.
+struct SlabCache {
+    free_list: *mut FreeObject,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            free_list: 0 as *mut FreeObject,
+            stats: CacheStats {
+                object_size: object_size,
+                slabs: 0,
+                allocated: 0,
+                free: 0,
+                allocations: 0,
+            },
+        }
+    }
+
+    fn allocate(&mut self, heap: &mut GrowableHeap) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            let slab = match heap.allocate(PAGE_SIZE, PAGE_SIZE) {
+                Some(slab) => slab,
+                None => return None,
+            };
+            self.add_slab(slab as usize);
+        }
+
+        let object = self.free_list;
+        self.free_list = unsafe { (*object).next };
+        self.stats.free -= 1;
+        self.stats.allocated += 1;
+        self.stats.allocations += 1;
+        Some(object as *mut u8)
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8) {
+        self.push(ptr as *mut FreeObject);
+        self.stats.allocated -= 1;
+    }
+
+    /// Splits the page at `start` into objects and adds them to the free list.
+    fn add_slab(&mut self, start: usize) {
+        let object_size = self.stats.object_size;
+        // push in reverse order, so that the objects are handed out in address order
+        for i in (0..(PAGE_SIZE / object_size)).rev() {
+            unsafe { self.push((start + i * object_size) as *mut FreeObject) };
+        }
+        self.stats.slabs += 1;
+    }
+
+    unsafe fn push(&mut self, object: *mut FreeObject) {
+        ptr::write(object, FreeObject { next: self.free_list });
+        self.free_list = object;
+        self.stats.free += 1;
+    }
+}
+
+/// The slab caches with the hole list heap that serves their slabs and the large allocations.
+struct SlabHeap {
+    caches: [SlabCache; CACHE_COUNT],
+    heap: GrowableHeap,
+}
+
+// the free lists only point into the heap, which is protected by the mutex
+unsafe impl Send for SlabHeap {}
+
+#[no_mangle]
+pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
+    let mut heap = HEAP.lock();
+    let heap = &mut *heap;
+    let ptr = match cache_index(size, align) {
+        Some(index) => heap.caches[index].allocate(&mut heap.heap),
+        None => heap.heap.allocate(size, align),
+    };
+    match ptr {
+        Some(ptr) => {
+            heap_stats::record_allocation(ptr, size);
+            ptr
+        }
+        None => {
+            heap_stats::record_failure();
+            panic!("out of memory");
+        }
+    }
+}
+
+#[no_mangle]
+pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
+    let mut heap = HEAP.lock();
+    match cache_index(size, align) {
+        Some(index) => unsafe { heap.caches[index].deallocate(ptr) },
+        None => unsafe { heap.heap.deallocate(ptr, size, align) },
+    }
+    heap_stats::record_deallocation(ptr, size);
+}
+
+#[no_mangle]
+pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
+    size
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate_inplace(_ptr: *mut u8, size: usize,
+    _new_size: usize, _align: usize) -> usize
+{
+    size
+}
+
+#[no_mangle]
+pub extern fn __rust_reallocate(ptr: *mut u8, size: usize, new_size: usize,
+                                align: usize) -> *mut u8 {
+    // the object is large enough if both sizes belong to the same cache
+    let old_index = cache_index(size, align);
+    if old_index.is_some() && old_index == cache_index(new_size, align) {
+        heap_stats::record_deallocation(ptr, size);
+        heap_stats::record_allocation(ptr, new_size);
+        return ptr;
+    }
+
+    let new_ptr = __rust_allocate(new_size, align);
+    unsafe { ptr::copy(ptr, new_ptr, cmp::min(size, new_size)) };
+    __rust_deallocate(ptr, size, align);
+    new_ptr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_index, CACHE_COUNT, MAX_OBJECT_SIZE, PAGE_SIZE};
+
+    #[test]
+    fn small_sizes_share_the_first_cache() {
+        assert_eq!(cache_index(0, 1), Some(0));
+        assert_eq!(cache_index(1, 1), Some(0));
+        assert_eq!(cache_index(8, 8), Some(0));
+    }
+
+    #[test]
+    fn sizes_are_rounded_up_to_a_power_of_two() {
+        assert_eq!(cache_index(9, 1), Some(1));
+        assert_eq!(cache_index(16, 1), Some(1));
+        assert_eq!(cache_index(100, 8), Some(4));
+        assert_eq!(cache_index(MAX_OBJECT_SIZE, 8), Some(CACHE_COUNT - 1));
+    }
+
+    #[test]
+    fn the_alignment_selects_a_larger_cache() {
+        assert_eq!(cache_index(8, 64), Some(3));
+        assert_eq!(cache_index(8, MAX_OBJECT_SIZE), Some(CACHE_COUNT - 1));
+        assert_eq!(cache_index(8, 2 * MAX_OBJECT_SIZE), None);
+    }
+
+    #[test]
+    fn large_sizes_are_not_served_from_slabs() {
+        assert_eq!(cache_index(MAX_OBJECT_SIZE + 1, 1), None);
+        assert_eq!(cache_index(PAGE_SIZE, 8), None);
+    }
+}
@@ -1,10 +1,19 @@
         global start            ; global here exports the label and makes it pub. entrypoint start
-        extern long_mode_start
-        section .text           ; default section of executable code
+        global p4_table
+        global stack_top
+        global gdt64_pointer
+        extern long_mode_trampoline
+
+        ;; The kernel is linked at this offset, but GRUB loads it at its physical address. Until
+        ;; paging is enabled, every symbol outside of the .boot section has to be accessed at
+        ;; `symbol - KERNEL_OFFSET`.
+        KERNEL_OFFSET equ 0xffffffff80000000
+
+        section .boot exec      ; linked at its physical address, so it can run without paging
         bits 32                 ; tells that the following instr are 32bit
 
 start:
-        mov esp, stack_top      ; Update stack pointer reg at start.
+        mov esp, stack_top - KERNEL_OFFSET ; Update stack pointer reg at start.
         ;; The first six integer or pointer argument is passed as registers
         ;; RDI, RSI, RDX, RCX, R8, and R9
         mov edi, ebx            ; Move multiboot info pointer to edi so we can use it later
@@ -19,7 +28,7 @@ start:
         call set_up_SSE         ; Enable SSE extension
 
         ;;  load the 64-bit GDT
-        lgdt [gdt64.pointer]
+        lgdt [gdt64_pointer_low - KERNEL_OFFSET]
 
         ;;  update selectors
         mov ax, gdt64.data
@@ -27,23 +36,26 @@ start:
         mov ds, ax              ; data selector
         mov es, ax              ; extra selector
 
-        jmp gdt64.code:long_mode_start
+        jmp gdt64.code:long_mode_trampoline
 
 set_up_page_tables:
-        ;;  recursive map P4
-        mov eax, p4_table
+        ;;  recursive map P4. Entry 511 is needed for the kernel, so entry 510 is used.
+        mov eax, p4_table - KERNEL_OFFSET
         or eax, 0b11            ; present + writable
-        mov [p4_table + 511 * 8], eax
+        mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
 
-        ;; Map the first P4 entry to P3 table
-        mov eax, p3_table
+        ;; Map the first and the last P4 entry to the same P3 table
+        mov eax, p3_table - KERNEL_OFFSET
         or eax, 0b11            ; Present + Writable bits set
-        mov [p4_table], eax
+        mov [p4_table - KERNEL_OFFSET], eax
+        mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
 
-        ;; Map the first P3 entry to P2 table
-        mov eax, p2_table
+        ;; Map the first P3 entry (identity mapping) and the P3 entry 510 (0xffffffff80000000)
+        ;; to the P2 table, so the first GB is reachable at both addresses
+        mov eax, p2_table - KERNEL_OFFSET
         or eax, 0b11
-        mov [p3_table], eax
+        mov [p3_table - KERNEL_OFFSET], eax
+        mov [p3_table - KERNEL_OFFSET + 510 * 8], eax
 
         ;; 512 entries in P1 and each entry in P1 can address page 4KiB in size, therefore 2MiB
         ;; map each P2 entry to huge 2MiB P1 pages
@@ -54,7 +66,7 @@ set_up_page_tables:
         mov eax, 0x200000       ; 2MiB
         mul ecx                 ; start address of ecx-th page
         or eax, 0b10000011      ; present + writable + huge
-        mov [p2_table + ecx * 8], eax ; map ecx-th entry
+        mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry
 
         inc ecx                 ; increase counter
         cmp ecx, 512            ; if counter == 512, the whole P2 table is mapped
@@ -64,7 +76,7 @@ set_up_page_tables:
 
 enable_paging:
         ;; load P4 to cr3 register
-        mov eax, p4_table
+        mov eax, p4_table - KERNEL_OFFSET
         mov cr3, eax
 
         ;; enable PAE-flag in cr4
@@ -191,8 +203,8 @@ p3_table:
         resb 4096
 p2_table:
         resb 4096
-stack_bottom:
-        resb 4096 * 2            ; Reserve 64 bytes for stact at bss section to avoid having unnecessary large file.
+stack_bottom:                   ; the boot page tables above are unmapped later, so they guard the stack
+        resb 4096 * 16          ; 64KiB boot stack, in .bss to avoid having an unnecessary large file.
 stack_top:
 
 
@@ -203,6 +215,10 @@ gdt64:
         dq (1<<44) | (1<<47) | (1<<41) | (1<<43) | (1<<53) ; code segment
 .data:  equ $ - gdt64
         dq (1<<44) | (1<<47) | (1<<41)                     ; data segment
-.pointer:
-        dw $ - gdt64 - 1        ; .pointer - gdt64 - 1 is length (2 bytes)
+.end:
+gdt64_pointer:
+        dw gdt64.end - gdt64 - 1 ; gdt64.end - gdt64 - 1 is length (2 bytes)
         dq gdt64                ; finally the address (8 bytes)
+gdt64_pointer_low:              ; the same GDT at its physical address, used before paging
+        dw gdt64.end - gdt64 - 1
+        dq gdt64 - KERNEL_OFFSET
@@ -1,16 +1,42 @@
-        global long_mode_start
+        global long_mode_trampoline
         extern rust_main
+        extern p4_table
+        extern stack_top
+        extern gdt64_pointer
+
+        KERNEL_OFFSET equ 0xffffffff80000000
+
+        section .boot exec
+        bits 64
+long_mode_trampoline:
+        ;; we are still running at the physical address, jump to the higher half
+        mov rax, long_mode_start
+        jmp rax
+
         section .text
         bits 64
 long_mode_start:
+        ;; switch to the higher half addresses of the stack and the GDT
+        mov rsp, stack_top
+        lgdt [gdt64_pointer]
+
+        ;; remove the identity mapping, it's not needed anymore
+        mov rax, p4_table
+        mov qword [rax], 0
+        mov rax, cr3
+        mov cr3, rax
+
+        ;; the multiboot info pointer in edi is a physical address, rust_main converts it
+        mov edi, edi            ; clear the upper half of rdi
+
         ;; call the rust main
         call rust_main
 .os_returned:
     ; rust main returned, print `OS returned!`
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [KERNEL_OFFSET + 0xb8000], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [KERNEL_OFFSET + 0xb8008], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [KERNEL_OFFSET + 0xb8010], rax
     hlt
\ No newline at end of file
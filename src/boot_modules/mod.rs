@@ -0,0 +1,109 @@
+//! The modules that the boot loader loads together with the kernel, e.g. an init program or an
+//! initial ramdisk.
+//!
+//! The Makefile adds a `module2` line to `grub.cfg` for every file in its `modules` variable,
+//! with the file name as command line. The frames of the modules are reserved by `memory::init`,
+//! and `init` maps them into the kernel address space. Only the first `MAX_MODULES` modules are
+//! used, the others are ignored and their memory might be handed out.
+
+use collections::Vec;
+use core::iter::{FilterMap, Take};
+use core::slice;
+use multiboot2::BootInformation;
+use spin::Mutex;
+use memory;
+use multiboot_tags::{self, Tag, TagIter, TAG_MODULE};
+
+/// The maximum number of modules. Their frames are reserved before the heap exists, so the
+/// ranges are kept in an array of fixed size.
+pub const MAX_MODULES: usize = 8;
+
+/// A module tag of the multiboot information structure.
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleTag {
+    /// The physical address of the first byte.
+    pub start: usize,
+    /// The physical address after the last byte.
+    pub end: usize,
+    pub cmdline: &'static str,
+}
+
+pub type ModuleTagIter = Take<FilterMap<TagIter, fn(Tag) -> Option<ModuleTag>>>;
+
+/// Returns the tags of the used modules, i.e. of the first `MAX_MODULES` ones.
+pub fn module_tags(boot_info: &BootInformation) -> ModuleTagIter {
+    all_module_tags(boot_info).take(MAX_MODULES)
+}
+
+fn all_module_tags(boot_info: &BootInformation)
+                   -> FilterMap<TagIter, fn(Tag) -> Option<ModuleTag>> {
+    multiboot_tags::tags(boot_info).filter_map(module_tag as fn(Tag) -> Option<ModuleTag>)
+}
+
+fn module_tag(tag: Tag) -> Option<ModuleTag> {
+    if tag.kind != TAG_MODULE || tag.size < 16 {
+        return None;
+    }
+    Some(ModuleTag {
+        start: tag.read_u32(8) as usize,
+        end: tag.read_u32(12) as usize,
+        cmdline: tag.string(16),
+    })
+}
+
+/// A module, mapped into the kernel address space.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub cmdline: &'static str,
+    pub data: &'static [u8],
+}
+
+impl Module {
+    /// The first word of the command line, which is the file name with the Makefile's `grub.cfg`.
+    pub fn name(&self) -> &'static str {
+        self.cmdline.split_whitespace().next().unwrap_or("")
+    }
+}
+
+static MODULES: Mutex<Option<Vec<Module>>> = Mutex::new(None);
+
+/// Maps the modules into the kernel address space. They are never unmapped.
+pub fn init(boot_info: &BootInformation) {
+    assert_has_not_been_called!("boot_modules::init must be called only once");
+
+    let mut modules = Vec::new();
+    for tag in module_tags(boot_info) {
+        let size = tag.end.saturating_sub(tag.start);
+        let data: &'static [u8] = if size == 0 {
+            &[]
+        } else {
+            let start = memory::map_physical_region(tag.start, size);
+            unsafe { slice::from_raw_parts(start as *const u8, size) }
+        };
+        klog!(Info, "module {:?}: {:#x} to {:#x}", tag.cmdline, tag.start, tag.end);
+        modules.push(Module {
+            cmdline: tag.cmdline,
+            data: data,
+        });
+    }
+    let ignored = all_module_tags(boot_info).count() - modules.len();
+    if ignored > 0 {
+        klog!(Warn,
+              "boot modules: only the first {} are used, ignoring {} more",
+              MAX_MODULES,
+              ignored);
+    }
+    *MODULES.lock() = Some(modules);
+}
+
+/// Returns all modules, in the order of `grub.cfg`.
+pub fn modules() -> Vec<Module> {
+    MODULES.lock().as_ref().expect("boot_modules::init was not called").clone()
+}
+
+/// Returns the first module called `name`.
+pub fn find(name: &str) -> Option<Module> {
+    let modules = MODULES.lock();
+    let modules = modules.as_ref().expect("boot_modules::init was not called");
+    modules.iter().find(|module| module.name() == name).cloned()
+}
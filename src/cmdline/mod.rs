@@ -0,0 +1,339 @@
+//! The kernel command line, e.g. `sched=fair heap=64M init=shell`.
+//!
+//! Arguments are separated by whitespace and are either `key=value` pairs or bare flags. The
+//! parameters that the kernel understands are registered together with their default and a
+//! help text. The ones of the core kernel are parsed into a `Config` by `init`; other
+//! subsystems `register` their own parameters and look them up with `value` and `flag`. The
+//! `help` flag prints all parameters.
+//!
+//! The command line is parsed before the heap exists, so everything is kept in arrays of fixed
+//! size. The strings point into the multiboot information structure, which stays mapped.
+
+use multiboot2::BootInformation;
+use spin::Mutex;
+use core::cmp;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use multiboot_tags::{self, TAG_COMMAND_LINE};
+use task::{self, Policy};
+
+/// The maximum number of registered parameters.
+const MAX_PARAMS: usize = 32;
+
+/// The maximum number of arguments on the command line. Further arguments are ignored.
+const MAX_ARGS: usize = 32;
+
+/// A parameter of the kernel command line.
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    /// The value that is used if the parameter isn't given, or `None` for a flag.
+    pub default: Option<&'static str>,
+    pub help: &'static str,
+}
+
+/// The parameters of the core kernel.
+const CORE_PARAMS: [Param; 7] = [Param {
+                                     name: "log",
+                                     default: Some("info"),
+                                     help: "log level: error, warn, info, debug or trace",
+                                 },
+                                 Param {
+                                     name: "console",
+                                     default: Some("vga"),
+                                     help: "console: vga",
+                                 },
+                                 Param {
+                                     name: "keyboard",
+                                     default: Some("us"),
+                                     help: "keyboard layout: us or de",
+                                 },
+                                 Param {
+                                     name: "sched",
+                                     default: Some(task::DEFAULT_POLICY),
+                                     help: "scheduler policy: rr, priority or fair",
+                                 },
+                                 Param {
+                                     name: "heap",
+                                     default: Some("1G"),
+                                     help: "maximum heap size in bytes, with an optional K, M \
+                                            or G suffix",
+                                 },
+                                 Param {
+                                     name: "init",
+                                     default: Some("init"),
+                                     help: "the boot module that is started as first program",
+                                 },
+                                 Param {
+                                     name: "help",
+                                     default: None,
+                                     help: "print the command line parameters",
+                                 }];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// The console that the kernel prints to. Only the VGA text buffer is supported so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Vga,
+}
+
+/// The layouts that the keyboard driver knows. The values index its key tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    Us = 0,
+    De = 1,
+}
+
+/// The settings of the core kernel.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub log_level: LogLevel,
+    pub console: Console,
+    pub keyboard_layout: KeyboardLayout,
+    pub scheduler: Policy,
+    /// The maximum size of the kernel heap in bytes.
+    pub heap_size: usize,
+    /// The name of the boot module that is started as first program.
+    pub init: &'static str,
+}
+
+struct CommandLine {
+    params: [Option<Param>; MAX_PARAMS],
+    args: [(&'static str, Option<&'static str>); MAX_ARGS],
+    arg_count: usize,
+    config: Option<Config>,
+}
+
+static COMMAND_LINE: Mutex<CommandLine> = Mutex::new(CommandLine {
+    params: [None; MAX_PARAMS],
+    args: [("", None); MAX_ARGS],
+    arg_count: 0,
+    config: None,
+});
+
+/// The most verbose `LogLevel` that `klog!` prints. Everything is printed until `init` sets it.
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Trace as usize);
+
+/// Reads the command line from the multiboot information and parses the core parameters.
+/// Invalid values are reported and replaced by the default.
+pub fn init(boot_info: &BootInformation) {
+    assert_has_not_been_called!("cmdline::init must be called only once");
+
+    let line = multiboot_tags::tags(boot_info)
+                   .find(|tag| tag.kind == TAG_COMMAND_LINE)
+                   .map_or("", |tag| tag.string(8));
+    {
+        let mut command_line = COMMAND_LINE.lock();
+        let count = parse(line, &mut command_line.args);
+        command_line.arg_count = cmp::min(count, MAX_ARGS);
+        if count > MAX_ARGS {
+            klog!(Warn, "cmdline: only the first {} arguments are used", MAX_ARGS);
+        }
+    }
+    for &param in CORE_PARAMS.iter() {
+        register(param);
+    }
+
+    let config = Config {
+        log_level: parse_value("log", parse_log_level),
+        console: parse_value("console", parse_console),
+        keyboard_layout: parse_value("keyboard", parse_keyboard_layout),
+        scheduler: parse_value("sched", Policy::from_name),
+        heap_size: parse_value("heap", parse_size),
+        init: value("init").unwrap(),
+    };
+    LOG_LEVEL.store(config.log_level as usize, Ordering::SeqCst);
+    COMMAND_LINE.lock().config = Some(config);
+
+    if flag("help") {
+        print_help();
+    }
+}
+
+/// Returns the settings of the core kernel.
+pub fn config() -> Config {
+    COMMAND_LINE.lock().config.expect("cmdline::init was not called")
+}
+
+/// Returns true if messages of `level` are printed, see `klog!`.
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as usize <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Declares a parameter. Panics if a parameter with the same name exists.
+pub fn register(param: Param) {
+    let mut command_line = COMMAND_LINE.lock();
+    assert!(command_line.find_param(param.name).is_none(),
+            "command line parameter {} is registered twice",
+            param.name);
+    let slot = command_line.params
+                           .iter_mut()
+                           .find(|slot| slot.is_none())
+                           .expect("too many command line parameters");
+    *slot = Some(param);
+}
+
+/// Returns the value of the registered parameter `name`, or its default if it isn't given.
+pub fn value(name: &str) -> Option<&'static str> {
+    let command_line = COMMAND_LINE.lock();
+    let param = command_line.find_param(name).expect("unregistered command line parameter");
+    match command_line.find_arg(name) {
+        Some(Some(value)) => Some(value),
+        _ => param.default,
+    }
+}
+
+/// Returns true if the registered flag `name` is given and not set to `0`, `no`, `off` or
+/// `false`.
+pub fn flag(name: &str) -> bool {
+    let command_line = COMMAND_LINE.lock();
+    command_line.find_param(name).expect("unregistered command line parameter");
+    match command_line.find_arg(name) {
+        Some(Some(value)) => !["0", "no", "off", "false"].contains(&value),
+        Some(None) => true,
+        None => false,
+    }
+}
+
+/// Prints the registered parameters with their defaults and help texts.
+pub fn print_help() {
+    let command_line = COMMAND_LINE.lock();
+    kprintln!("command line parameters:");
+    for param in command_line.params.iter().filter_map(|param| param.as_ref()) {
+        match param.default {
+            Some(default) => kprintln!("  {}={} (default)  {}", param.name, default, param.help),
+            None => kprintln!("  {}  {}", param.name, param.help),
+        }
+    }
+}
+
+impl CommandLine {
+    fn find_param(&self, name: &str) -> Option<Param> {
+        self.params.iter().filter_map(|&param| param).find(|param| param.name == name)
+    }
+
+    /// Returns the value of the argument `name`, which is `None` for a bare flag. The last
+    /// occurrence wins.
+    fn find_arg(&self, name: &str) -> Option<Option<&'static str>> {
+        self.args[..self.arg_count]
+            .iter()
+            .rev()
+            .find(|&&(key, _)| key == name)
+            .map(|&(_, value)| value)
+    }
+}
+
+/// Splits `line` into `key=value` pairs and bare flags. Returns the number of arguments, which
+/// is larger than `args.len()` if not all of them fit.
+fn parse(line: &'static str, args: &mut [(&'static str, Option<&'static str>)]) -> usize {
+    let mut count = 0;
+    for word in line.split_whitespace() {
+        if let Some(slot) = args.get_mut(count) {
+            *slot = match word.find('=') {
+                Some(index) => (&word[..index], Some(&word[(index + 1)..])),
+                None => (word, None),
+            };
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Parses the value of the core parameter `name`. An invalid value is reported and the default
+/// is used instead.
+fn parse_value<T, F>(name: &str, parse: F) -> T
+    where F: Fn(&str) -> Option<T>
+{
+    let value = value(name).unwrap();
+    match parse(value) {
+        Some(parsed) => parsed,
+        None => {
+            let default = COMMAND_LINE.lock().find_param(name).unwrap().default.unwrap();
+            klog!(Warn, "cmdline: invalid value {:?} for {}, using {}", value, name, default);
+            parse(default).expect("invalid default")
+        }
+    }
+}
+
+fn parse_log_level(value: &str) -> Option<LogLevel> {
+    match value {
+        "error" => Some(LogLevel::Error),
+        "warn" => Some(LogLevel::Warn),
+        "info" => Some(LogLevel::Info),
+        "debug" => Some(LogLevel::Debug),
+        "trace" => Some(LogLevel::Trace),
+        _ => None,
+    }
+}
+
+fn parse_console(value: &str) -> Option<Console> {
+    match value {
+        "vga" => Some(Console::Vga),
+        _ => None,
+    }
+}
+
+fn parse_keyboard_layout(value: &str) -> Option<KeyboardLayout> {
+    match value {
+        "us" => Some(KeyboardLayout::Us),
+        "de" => Some(KeyboardLayout::De),
+        _ => None,
+    }
+}
+
+/// Parses a number of bytes with an optional `K`, `M` or `G` suffix. The heap can't grow beyond
+/// its window, so larger sizes are invalid.
+fn parse_size(value: &str) -> Option<usize> {
+    use heap::{HEAP_SIZE, HEAP_WINDOW_SIZE};
+
+    let (digits, unit) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>()
+          .ok()
+          .and_then(|number| number.checked_mul(unit))
+          .and_then(|size| if size >= HEAP_SIZE && size <= HEAP_WINDOW_SIZE {
+              Some(size)
+          } else {
+              None
+          })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_size};
+
+    #[test]
+    fn splits_arguments() {
+        let mut args = [("", None); 4];
+        let count = parse(" sched=fair  help init=a=b heap= ", &mut args);
+        assert_eq!(count, 4);
+        assert_eq!(args,
+                   [("sched", Some("fair")),
+                    ("help", None),
+                    ("init", Some("a=b")),
+                    ("heap", Some(""))]);
+        assert_eq!(parse("a b c d e", &mut args), 5);
+        assert_eq!(args[3], ("d", None));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1M"), Some(1 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("204800"), Some(200 * 1024));
+        assert_eq!(parse_size("64K"), None);
+        assert_eq!(parse_size("2G"), None);
+        assert_eq!(parse_size("M"), None);
+    }
+}
@@ -0,0 +1,291 @@
+//! Loading of ELF64 executables into user address spaces.
+//!
+//! Every `PT_LOAD` segment becomes an anonymous area with the access rights of the segment.
+//! Segments that share a page, like the text and data segments of static executables from GNU
+//! ld, share an area with the rights of both. The file contents are copied in, and the rest of
+//! the area, e.g. `.bss`, stays zeroed. Position
+//! independent executables are loaded at `PIE_BASE` and their relative relocations are applied.
+//!
+//! The stack is laid out as the System V ABI expects it at the entry point: the stack pointer
+//! points to `argc`, followed by the `argv` and `envp` pointer arrays and the auxiliary vector.
+//! The strings they point to are at the top of the stack.
+
+use collections::Vec;
+use memory::{self, AddressSpace, Backing, EntryFlags, Vma, PAGE_SIZE, USER_AREAS_START,
+             USER_STACK_TOP};
+use super::{Elf, Error, PT_LOAD, PT_DYNAMIC, PF_X, read_u64};
+
+/// The load address of position independent executables.
+const PIE_BASE: usize = 0x40_0000;
+
+/// The size of the user stack.
+const STACK_SIZE: usize = 64 * PAGE_SIZE;
+
+/// The maximum size of the strings, pointer arrays and auxiliary vector at the stack top.
+const MAX_ARGUMENTS_SIZE: usize = 16 * PAGE_SIZE;
+
+// The entries of the dynamic section that are needed for relocations.
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const RELA_SIZE: usize = 24;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+// The types of the auxiliary vector entries.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_RANDOM: u64 = 25;
+
+/// A page aligned part of the program area that holds one or more segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadArea {
+    pub start: usize,
+    pub end: usize,
+    /// The `PF_*` flags of all its segments.
+    pub flags: u32,
+}
+
+/// Where a loaded program starts.
+#[derive(Debug, Clone, Copy)]
+pub struct Image {
+    pub entry: usize,
+    pub stack_pointer: usize,
+}
+
+/// Loads the executable in `data` into `space`, which must not have any areas yet, and creates
+/// its stack with the arguments `argv` and the environment `envp`.
+pub fn load(space: &mut AddressSpace,
+            data: &[u8],
+            argv: &[&[u8]],
+            envp: &[&[u8]])
+            -> Result<Image, Error> {
+    let elf = try!(Elf::parse(data));
+    let base = if elf.is_position_independent() { PIE_BASE } else { 0 };
+
+    for area in try!(load_areas(&elf, base)) {
+        let vma = Vma::new(area.start,
+                           area.end,
+                           EntryFlags::from_elf_program_flags(area.flags),
+                           Backing::Anonymous);
+        try!(space.insert_vma(vma));
+    }
+    for header in elf.program_headers().filter(|header| header.kind == PT_LOAD) {
+        // the pages are zeroed, so the part after the file contents is zero, too
+        try!(space.write(base + header.virtual_address, elf.segment_data(&header)));
+    }
+    if elf.is_position_independent() {
+        try!(relocate(space, &elf, base));
+    }
+
+    let executable = elf.program_headers().any(|header| {
+        header.kind == PT_LOAD && header.flags & PF_X != 0 &&
+        elf.entry() >= header.virtual_address &&
+        elf.entry() - header.virtual_address < header.memory_size
+    });
+    if !executable {
+        return Err(Error::Malformed);
+    }
+    let entry = base + elf.entry();
+
+    let stack = Vma::new(USER_STACK_TOP - STACK_SIZE,
+                         USER_STACK_TOP,
+                         memory::user_page_flags(true, false),
+                         Backing::Anonymous);
+    try!(space.insert_vma(stack));
+
+    let mut auxv = vec![(AT_PAGESZ, PAGE_SIZE as u64),
+                        (AT_BASE, 0),
+                        (AT_ENTRY, entry as u64),
+                        (AT_UID, 0),
+                        (AT_EUID, 0),
+                        (AT_GID, 0),
+                        (AT_EGID, 0)];
+    if let Some(address) = elf.program_headers_address() {
+        auxv.push((AT_PHDR, (base + address) as u64));
+        auxv.push((AT_PHENT, super::PROGRAM_HEADER_SIZE as u64));
+        auxv.push((AT_PHNUM, elf.program_header_count() as u64));
+    }
+    let stack_pointer = try!(build_stack(space, argv, envp, &auxv));
+
+    Ok(Image {
+        entry: entry,
+        stack_pointer: stack_pointer,
+    })
+}
+
+/// Returns the areas of the `PT_LOAD` segments of `elf` when it is loaded at `base`. The
+/// segments must be sorted by address and must not overlap, but they may share a page.
+pub fn load_areas(elf: &Elf, base: usize) -> Result<Vec<LoadArea>, Error> {
+    let mut areas: Vec<LoadArea> = Vec::new();
+    let mut previous_end = 0;
+    for header in elf.program_headers().filter(|header| header.kind == PT_LOAD) {
+        if header.memory_size == 0 {
+            continue;
+        }
+        // programs are loaded below the areas that are allocated at runtime
+        let end = base.checked_add(header.virtual_address)
+            .and_then(|start| start.checked_add(header.memory_size));
+        match end {
+            Some(end) if end <= USER_AREAS_START => {}
+            _ => return Err(Error::Malformed),
+        }
+        let start = base + header.virtual_address;
+        let end = start + header.memory_size;
+        if start < previous_end {
+            return Err(Error::OverlappingSegments);
+        }
+        previous_end = end;
+
+        let shares_page = areas.last().map_or(false, |area| page_align_down(start) < area.end);
+        if shares_page {
+            let area = areas.last_mut().unwrap();
+            area.end = page_align_up(end);
+            area.flags |= header.flags;
+        } else {
+            areas.push(LoadArea {
+                start: page_align_down(start),
+                end: page_align_up(end),
+                flags: header.flags,
+            });
+        }
+    }
+    Ok(areas)
+}
+
+/// Applies the relocations of a position independent executable that was loaded at `base`.
+/// Static executables only have relative relocations.
+fn relocate(space: &mut AddressSpace, elf: &Elf, base: usize) -> Result<(), Error> {
+    let dynamic = match elf.program_headers().find(|header| header.kind == PT_DYNAMIC) {
+        Some(header) => elf.segment_data(&header),
+        None => return Ok(()),
+    };
+
+    let (mut table, mut table_size, mut entry_size) = (None, 0, RELA_SIZE);
+    for entry in dynamic.chunks(16).filter(|entry| entry.len() == 16) {
+        let (tag, value) = (read_u64(entry, 0), read_u64(entry, 8) as usize);
+        match tag {
+            DT_NULL => break,
+            DT_RELA => table = Some(value),
+            DT_RELASZ => table_size = value,
+            DT_RELAENT => entry_size = value,
+            _ => {}
+        }
+    }
+    let table = match table {
+        Some(address) => try!(elf.data_at(address, table_size).ok_or(Error::Malformed)),
+        None => return Ok(()),
+    };
+    if entry_size < RELA_SIZE {
+        return Err(Error::Malformed);
+    }
+
+    for relocation in table.chunks(entry_size).filter(|entry| entry.len() >= RELA_SIZE) {
+        let offset = read_u64(relocation, 0) as usize;
+        let kind = read_u64(relocation, 8) as u32;
+        let addend = read_u64(relocation, 16);
+        match kind {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                let value = (base as u64).wrapping_add(addend);
+                try!(space.write(base.wrapping_add(offset), &to_le_bytes(value)));
+            }
+            _ => return Err(Error::UnsupportedRelocation(kind)),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the strings, the pointer arrays and the auxiliary vector to the top of the stack.
+/// Returns the initial stack pointer.
+fn build_stack(space: &mut AddressSpace,
+               argv: &[&[u8]],
+               envp: &[&[u8]],
+               auxv: &[(u64, u64)])
+               -> Result<usize, Error> {
+    const RANDOM_SIZE: usize = 16;
+
+    let strings_size = argv.iter().chain(envp).fold(RANDOM_SIZE, |size, s| size + s.len() + 1);
+    // argc, argv and envp with their terminating null pointers, and the auxiliary vector with
+    // `AT_RANDOM` and `AT_NULL`
+    let word_count = 1 + argv.len() + 1 + envp.len() + 1 + (auxv.len() + 2) * 2;
+    if strings_size + word_count * 8 + 16 > MAX_ARGUMENTS_SIZE {
+        return Err(Error::ArgumentsTooLong);
+    }
+    let strings_start = USER_STACK_TOP - strings_size;
+    // the stack pointer is 16-byte aligned at the entry point
+    let stack_pointer = (strings_start - word_count * 8) & !0xf;
+
+    let mut strings = Vec::with_capacity(strings_size);
+    strings.extend_from_slice(&random_bytes());
+    let mut words = Vec::with_capacity(word_count);
+    words.push(argv.len() as u64);
+    for list in &[argv, envp] {
+        for s in list.iter() {
+            words.push((strings_start + strings.len()) as u64);
+            strings.extend_from_slice(s);
+            strings.push(0);
+        }
+        words.push(0);
+    }
+    for &(kind, value) in auxv.iter().chain(&[(AT_RANDOM, strings_start as u64), (AT_NULL, 0)]) {
+        words.push(kind);
+        words.push(value);
+    }
+
+    let mut image = Vec::with_capacity(USER_STACK_TOP - stack_pointer);
+    for &word in &words {
+        image.extend_from_slice(&to_le_bytes(word));
+    }
+    while stack_pointer + image.len() < strings_start {
+        image.push(0);
+    }
+    image.extend_from_slice(&strings);
+    try!(space.write(stack_pointer, &image));
+    Ok(stack_pointer)
+}
+
+/// Returns 16 bytes for `AT_RANDOM`, which the C library uses e.g. for stack canaries. There is
+/// no entropy source yet, so they are derived from the time stamp counter.
+fn random_bytes() -> [u8; 16] {
+    let (high, low): (u32, u32);
+    unsafe { asm!("rdtsc" : "={edx}"(high), "={eax}"(low) ::: "volatile") };
+    let mut state = (high as u64) << 32 | low as u64 | 1;
+
+    let mut bytes = [0; 16];
+    for chunk in bytes.chunks_mut(8) {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&to_le_bytes(state));
+    }
+    bytes
+}
+
+fn to_le_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+fn page_align_down(address: usize) -> usize {
+    address / PAGE_SIZE * PAGE_SIZE
+}
+
+fn page_align_up(address: usize) -> usize {
+    (address + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
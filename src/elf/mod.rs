@@ -0,0 +1,354 @@
+//! Parsing of ELF64 executables.
+//!
+//! Only what is needed to load a program is parsed: the file header and the program headers.
+//! Both are read field by field, so the file doesn't have to be aligned in memory.
+
+pub use self::loader::{Image, load};
+
+use memory::{PageFaultError, VmaError};
+
+mod loader;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const CURRENT_VERSION: u8 = 1;
+const MACHINE_X86_64: u16 = 62;
+
+/// An executable that is loaded at the addresses in its program headers.
+pub const ET_EXEC: u16 = 2;
+/// A position independent executable or a shared library.
+pub const ET_DYN: u16 = 3;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The file doesn't start with the ELF magic number.
+    NotElf,
+    /// The file is not a little endian ELF64 executable for x86_64.
+    Unsupported,
+    /// The program needs a dynamic linker.
+    Dynamic,
+    /// A header or segment lies outside the file, or a segment outside the program area.
+    Malformed,
+    /// Two segments overlap, or they aren't sorted by address.
+    OverlappingSegments,
+    /// A position independent executable has a relocation of this type, which isn't supported.
+    UnsupportedRelocation(u32),
+    /// The arguments and environment don't fit on the stack.
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+impl From<VmaError> for Error {
+    fn from(error: VmaError) -> Error {
+        match error {
+            VmaError::Overlap => Error::OverlappingSegments,
+            _ => Error::OutOfMemory,
+        }
+    }
+}
+
+impl From<PageFaultError> for Error {
+    fn from(error: PageFaultError) -> Error {
+        match error {
+            PageFaultError::OutOfMemory => Error::OutOfMemory,
+            // `AddressSpace::write` waits for the locks
+            PageFaultError::Locked => unreachable!("locked address space while loading"),
+            // e.g. a relocation outside of the segments
+            _ => Error::Malformed,
+        }
+    }
+}
+
+/// A program header, which describes a segment.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: usize,
+    pub virtual_address: usize,
+    pub file_size: usize,
+    pub memory_size: usize,
+}
+
+/// A validated ELF64 executable.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    kind: u16,
+    entry: usize,
+    program_headers_offset: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the file header and the program headers of the executable in `data`. All loadable
+    /// segments lie inside `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, Error> {
+        if data.len() < HEADER_SIZE || &data[0..4] != &MAGIC[..] {
+            return Err(Error::NotElf);
+        }
+        if data[4] != CLASS_64 || data[5] != LITTLE_ENDIAN || data[6] != CURRENT_VERSION ||
+           read_u16(data, 18) != MACHINE_X86_64 {
+            return Err(Error::Unsupported);
+        }
+        let kind = read_u16(data, 16);
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(Error::Unsupported);
+        }
+
+        let elf = Elf {
+            data: data,
+            kind: kind,
+            entry: read_u64(data, 24) as usize,
+            program_headers_offset: read_u64(data, 32) as usize,
+            program_header_count: read_u16(data, 56) as usize,
+        };
+        if read_u16(data, 54) as usize != PROGRAM_HEADER_SIZE {
+            return Err(Error::Malformed);
+        }
+        let table_end = elf.program_header_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(elf.program_headers_offset));
+        match table_end {
+            Some(end) if end <= data.len() => {}
+            _ => return Err(Error::Malformed),
+        }
+
+        let mut loadable = false;
+        for header in elf.program_headers() {
+            match header.kind {
+                PT_INTERP => return Err(Error::Dynamic),
+                PT_LOAD | PT_DYNAMIC => {
+                    let file_end = header.offset.checked_add(header.file_size);
+                    let memory_end = header.virtual_address.checked_add(header.memory_size);
+                    if file_end.map_or(true, |end| end > data.len()) || memory_end.is_none() ||
+                       header.file_size > header.memory_size {
+                        return Err(Error::Malformed);
+                    }
+                    loadable |= header.kind == PT_LOAD;
+                }
+                _ => {}
+            }
+        }
+        if !loadable {
+            return Err(Error::Malformed);
+        }
+        Ok(elf)
+    }
+
+    /// Returns true for a position independent executable, which can be loaded at any address.
+    pub fn is_position_independent(&self) -> bool {
+        self.kind == ET_DYN
+    }
+
+    /// The address of the first instruction, relative to the load address for position
+    /// independent executables.
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> ProgramHeaderIter<'a> {
+        ProgramHeaderIter {
+            data: self.data,
+            offset: self.program_headers_offset,
+            remaining: self.program_header_count,
+        }
+    }
+
+    /// Returns the file contents of the segment.
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        &self.data[header.offset..(header.offset + header.file_size)]
+    }
+
+    /// Returns the `size` bytes of file contents at `virtual_address`, if they lie in a loadable
+    /// segment.
+    pub fn data_at(&self, virtual_address: usize, size: usize) -> Option<&'a [u8]> {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| {
+                virtual_address >= header.virtual_address &&
+                virtual_address - header.virtual_address <= header.file_size &&
+                size <= header.file_size - (virtual_address - header.virtual_address)
+            })
+            .map(|header| {
+                let offset = header.offset + virtual_address - header.virtual_address;
+                &self.data[offset..(offset + size)]
+            })
+    }
+
+    /// Returns the virtual address of the program headers, if they are loaded with the program.
+    pub fn program_headers_address(&self) -> Option<usize> {
+        if let Some(header) = self.program_headers().find(|header| header.kind == PT_PHDR) {
+            return Some(header.virtual_address);
+        }
+        let offset = self.program_headers_offset;
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| offset >= header.offset && offset - header.offset < header.file_size)
+            .map(|header| header.virtual_address + offset - header.offset)
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.program_header_count
+    }
+}
+
+pub struct ProgramHeaderIter<'a> {
+    data: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ProgramHeaderIter<'a> {
+    type Item = ProgramHeader;
+
+    fn next(&mut self) -> Option<ProgramHeader> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let data = &self.data[self.offset..(self.offset + PROGRAM_HEADER_SIZE)];
+        self.offset += PROGRAM_HEADER_SIZE;
+        self.remaining -= 1;
+        Some(ProgramHeader {
+            kind: read_u32(data, 0),
+            flags: read_u32(data, 4),
+            offset: read_u64(data, 8) as usize,
+            virtual_address: read_u64(data, 16) as usize,
+            file_size: read_u64(data, 32) as usize,
+            memory_size: read_u64(data, 40) as usize,
+        })
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    read_le(data, offset, 2) as u16
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    read_le(data, offset, 4) as u32
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    read_le(data, offset, 8)
+}
+
+/// Reads the little endian number of `size` bytes at `offset`.
+fn read_le(data: &[u8], offset: usize, size: usize) -> u64 {
+    data[offset..(offset + size)]
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::loader::{LoadArea, load_areas};
+
+    /// Returns an executable with one segment of `size` bytes at 0x400000, which follows the
+    /// headers in the file.
+    fn executable(size: usize) -> [u8; 256] {
+        let mut data = [0; 256];
+        data[0..4].copy_from_slice(&MAGIC);
+        data[4] = CLASS_64;
+        data[5] = LITTLE_ENDIAN;
+        data[6] = CURRENT_VERSION;
+        data[16] = ET_EXEC as u8;
+        data[18] = MACHINE_X86_64 as u8;
+        data[24..32].copy_from_slice(&[0x78, 0, 0x40, 0, 0, 0, 0, 0]);
+        data[32] = HEADER_SIZE as u8;
+        data[54] = PROGRAM_HEADER_SIZE as u8;
+        data[56] = 1;
+
+        let header = &mut data[HEADER_SIZE..];
+        header[0] = PT_LOAD as u8;
+        header[4] = 0b101;
+        header[16..24].copy_from_slice(&[0, 0, 0x40, 0, 0, 0, 0, 0]);
+        header[32] = size as u8;
+        header[40] = size as u8;
+        data
+    }
+
+    #[test]
+    fn parses_program_headers() {
+        let data = executable(0x80);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry(), 0x400078);
+        assert!(!elf.is_position_independent());
+        let header = elf.program_headers().next().unwrap();
+        assert_eq!(header.kind, PT_LOAD);
+        assert_eq!(header.virtual_address, 0x400000);
+        assert_eq!(elf.segment_data(&header).len(), 0x80);
+        assert_eq!(elf.program_headers_address(), Some(0x400040));
+        assert_eq!(elf.data_at(0x400078, 8), Some(&data[0x78..0x80]));
+        assert_eq!(elf.data_at(0x400078, 9), None);
+    }
+
+    /// Adds a second segment of `size` bytes at `address` with the `PF_*` flags `flags`. Its
+    /// contents are the first bytes of the file.
+    fn add_segment(data: &mut [u8; 256], address: usize, size: usize, flags: u32) {
+        data[56] = 2;
+        let header = &mut data[(HEADER_SIZE + PROGRAM_HEADER_SIZE)..];
+        header[0] = PT_LOAD as u8;
+        header[4] = flags as u8;
+        for (i, byte) in header[16..24].iter_mut().enumerate() {
+            *byte = (address >> (i * 8)) as u8;
+        }
+        header[32] = size as u8;
+        header[40] = size as u8;
+    }
+
+    #[test]
+    fn segments_in_one_page_share_an_area() {
+        let mut data = executable(0x80);
+        // a writable data segment right after the text segment, like GNU ld places them
+        add_segment(&mut data, 0x400080, 0x10, 0b110);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(load_areas(&elf, 0).unwrap(),
+                   vec![LoadArea {
+                            start: 0x400000,
+                            end: 0x401000,
+                            flags: 0b111,
+                        }]);
+
+        add_segment(&mut data, 0x401010, 0x10, 0b110);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(load_areas(&elf, 0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let mut data = executable(0x80);
+        add_segment(&mut data, 0x400070, 0x10, 0b110);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(load_areas(&elf, 0).err(), Some(Error::OverlappingSegments));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut data = executable(0x80);
+        data[18] = 3;
+        assert_eq!(Elf::parse(&data).err(), Some(Error::Unsupported));
+        data[0] = 0;
+        assert_eq!(Elf::parse(&data).err(), Some(Error::NotElf));
+    }
+
+    #[test]
+    fn rejects_segments_outside_the_file() {
+        let mut data = executable(0x80);
+        // 0x180 bytes
+        data[HEADER_SIZE + 33] = 1;
+        data[HEADER_SIZE + 41] = 1;
+        assert_eq!(Elf::parse(&data).err(), Some(Error::Malformed));
+    }
+}
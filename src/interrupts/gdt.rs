@@ -0,0 +1,124 @@
+//! The GDT with user segments and the TSS.
+//!
+//! The boot GDT in `boot.asm` only has kernel segments and lives in `.rodata`, but loading a TSS
+//! writes its busy bit to the GDT. So `init` replaces it with a GDT in `.bss` that has the same
+//! kernel segments at the same selectors.
+
+use core::mem::size_of;
+use x86::dtables::{DescriptorTablePointer, lgdt};
+
+pub const KERNEL_CODE_SELECTOR: u16 = 1 << 3;
+pub const KERNEL_DATA_SELECTOR: u16 = 2 << 3;
+// `sysret` expects the user data segment directly below the user code segment
+pub const USER_DATA_SELECTOR: u16 = 3 << 3 | 3;
+pub const USER_CODE_SELECTOR: u16 = 4 << 3 | 3;
+pub const TSS_SELECTOR: u16 = 5 << 3;
+
+/// The IST entry that the double fault handler runs on, so that a kernel stack overflow can be
+/// reported. The IDT refers to it as `DOUBLE_FAULT_IST_INDEX + 1`.
+pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+
+const DESCRIPTOR_WRITABLE: u64 = 1 << 41;
+const DESCRIPTOR_EXECUTABLE: u64 = 1 << 43;
+const DESCRIPTOR_USER_SEGMENT: u64 = 1 << 44;
+const DESCRIPTOR_DPL_3: u64 = 3 << 45;
+const DESCRIPTOR_PRESENT: u64 = 1 << 47;
+const DESCRIPTOR_LONG_MODE: u64 = 1 << 53;
+const DESCRIPTOR_TSS_AVAILABLE: u64 = 0b1001 << 40;
+
+const KERNEL_CODE: u64 = DESCRIPTOR_USER_SEGMENT | DESCRIPTOR_PRESENT | DESCRIPTOR_WRITABLE |
+                         DESCRIPTOR_EXECUTABLE | DESCRIPTOR_LONG_MODE;
+const KERNEL_DATA: u64 = DESCRIPTOR_USER_SEGMENT | DESCRIPTOR_PRESENT | DESCRIPTOR_WRITABLE;
+
+/// The 64-bit task state segment. It only holds stack pointers: `privilege_stack_table[0]` is
+/// loaded when an interrupt arrives in user mode, the interrupt stack table is used by the IDT
+/// entries that have a stack index.
+#[repr(C, packed)]
+struct TaskStateSegment {
+    reserved_1: u32,
+    privilege_stack_table: [u64; 3],
+    reserved_2: u64,
+    interrupt_stack_table: [u64; 7],
+    reserved_3: u64,
+    reserved_4: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            reserved_3: 0,
+            reserved_4: 0,
+            // the size of the TSS, i.e. there is no I/O permission bitmap
+            iomap_base: 104,
+        }
+    }
+}
+
+/// Only written by `init` and, with interrupts disabled, by `set_kernel_stack`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Null, kernel code, kernel data, user data, user code and the two entries of the TSS.
+static mut GDT: [u64; 7] = [0; 7];
+
+/// Loads the GDT and the TSS. The double fault handler runs on the stack that ends at
+/// `double_fault_stack`.
+pub fn init(double_fault_stack: usize) {
+    assert_has_not_been_called!("gdt::init must be called only once");
+
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = double_fault_stack as u64;
+
+        let (tss_low, tss_high) = tss_descriptor(&TSS);
+        GDT = [0,
+               KERNEL_CODE,
+               KERNEL_DATA,
+               KERNEL_DATA | DESCRIPTOR_DPL_3,
+               KERNEL_CODE | DESCRIPTOR_DPL_3,
+               tss_low,
+               tss_high];
+
+        let pointer = DescriptorTablePointer {
+            base: GDT.as_ptr() as u64,
+            limit: (size_of::<[u64; 7]>() - 1) as u16,
+        };
+        lgdt(&pointer);
+
+        // the kernel code segment is unchanged, so CS doesn't need to be reloaded
+        asm!("mov ss, $0
+              mov ds, $0
+              mov es, $0
+              ltr $1"
+             :: "r"(KERNEL_DATA_SELECTOR), "r"(TSS_SELECTOR)
+             :: "volatile", "intel");
+    }
+}
+
+/// The top of the kernel stack of the running thread. The `syscall` entry switches to it, because
+/// unlike an interrupt, `syscall` doesn't load a stack pointer from the TSS.
+#[no_mangle]
+pub static mut SYSCALL_KERNEL_STACK: usize = 0;
+
+/// Sets the stack that interrupts, exceptions and system calls in user mode switch to. It is the
+/// kernel stack of the running thread.
+pub fn set_kernel_stack(stack_top: usize) {
+    unsafe {
+        TSS.privilege_stack_table[0] = stack_top as u64;
+        SYSCALL_KERNEL_STACK = stack_top;
+    }
+}
+
+/// Returns the two GDT entries of a 64-bit TSS descriptor.
+fn tss_descriptor(tss: &TaskStateSegment) -> (u64, u64) {
+    let base = tss as *const _ as u64;
+    let limit = (size_of::<TaskStateSegment>() - 1) as u64;
+
+    let low = limit & 0xffff | (base & 0xff_ffff) << 16 | DESCRIPTOR_TSS_AVAILABLE |
+              DESCRIPTOR_PRESENT | (base >> 24 & 0xff) << 56;
+    let high = base >> 32;
+    (low, high)
+}
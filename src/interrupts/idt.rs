@@ -1,9 +1,14 @@
 use x86::segmentation::{SegmentSelector};
 use bit_field::BitField;
 
+/// Creates an IDT entry that saves all general purpose registers, calls `$body` and returns
+/// with `iretq`. The saved registers belong to the interrupted code, so `$body` may switch to
+/// another thread and return much later.
+///
+/// With the `|frame|` form, `$body` gets the `ExceptionStackFrame` that the CPU pushed.
 macro_rules! make_idt_entry {
-    ($name:ident, $body:expr) => {{
-        fn body() {
+    ($name:ident, |$frame:ident| $body:expr) => {{
+        fn body($frame: &idt::ExceptionStackFrame) {
             $body
         }
         use self::idt::Entry;
@@ -23,14 +28,74 @@ macro_rules! make_idt_entry {
                   push rdx
                   push rcx
                   push rbx
+                  push rax
 
-                  mov rsi, rsp
+                  lea rdi, [rsp + 15 * 8]
+                  call $0
+
+                  pop rax
+                  pop rbx
+                  pop rcx
+                  pop rdx
+                  pop rdi
+                  pop rsi
+                  pop r8
+                  pop r9
+                  pop r10
+                  pop r11
+                  pop r12
+                  pop r13
+                  pop r14
+                  pop r15
+                  pop rbp
+
+                  iretq" :: "s"(body as fn(&idt::ExceptionStackFrame)) :: "volatile", "intel");
+            intrinsics::unreachable();
+        }
+
+        Entry::new(segmentation::cs(), $name)
+    }};
+    ($name:ident, $body:expr) => {
+        make_idt_entry!($name, |_frame| $body)
+    };
+}
+
+/// Like `make_idt_entry!`, but for exceptions that push an error code. The exception stack frame
+/// and the error code are passed to the body, and the error code is removed from the stack
+/// before returning.
+macro_rules! make_idt_entry_with_error_code {
+    ($name:ident, |$frame:ident, $error_code:ident| $body:expr) => {{
+        fn body($frame: &idt::ExceptionStackFrame, $error_code: u64) {
+            $body
+        }
+        use self::idt::Entry;
+        #[naked]
+        unsafe extern fn $name() {
+            asm!("push rbp
+                  push r15
+                  push r14
+                  push r13
+                  push r12
+                  push r11
+                  push r10
+                  push r9
+                  push r8
                   push rsi
-                  
+                  push rdi
+                  push rdx
+                  push rcx
+                  push rbx
+                  push rax
+
+                  lea rdi, [rsp + 16 * 8]
+                  mov rsi, [rsp + 15 * 8]
+                  sub rsp, 8
+
                   call $0
 
                   add rsp, 8
 
+                  pop rax
                   pop rbx
                   pop rcx
                   pop rdx
@@ -46,7 +111,10 @@ macro_rules! make_idt_entry {
                   pop r15
                   pop rbp
 
-                  iretq" :: "s"(body as fn()) :: "volatile", "intel");
+                  add rsp, 8
+
+                  iretq" :: "s"(body as fn(&idt::ExceptionStackFrame, u64))
+                        :: "volatile", "intel");
             intrinsics::unreachable();
         }
 
@@ -54,6 +122,29 @@ macro_rules! make_idt_entry {
     }}
 }
 
+/// The frame that the CPU pushes on an interrupt, which `iretq` returns through.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionStackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64,
+}
+
+impl ExceptionStackFrame {
+    /// Returns true if the interrupted code ran in ring 3.
+    pub fn from_user_mode(&self) -> bool {
+        self.code_segment & 0b11 == 3
+    }
+
+    /// Returns true if interrupts were enabled in the interrupted code.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.cpu_flags & (1 << 9) != 0
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct Entry {
@@ -1,24 +1,27 @@
 #[macro_use]
 mod idt;
+pub mod gdt;
 
 use vga;
-use x86::{irq, segmentation};
+use memory;
+use x86::{irq, segmentation, controlregs};
 use pic;
+use task;
+use process::{self, ExitStatus};
+use syscall;
 use keyboard::{Keyboard, STATE};
 use cpuio::Port;
-use core::intrinsics;
+use core::{intrinsics, mem};
 
 lazy_static! {
 	static ref IDT: idt::Idt = {
 		let mut idt = idt::Idt::new();
-        idt.set_handler(0, make_idt_entry!(isr0, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Divide By Zero")) };
-            loop { } 
+        idt.set_handler(0, make_idt_entry!(isr0, |frame| {
+            exception(frame, "Divide By Zero", None)
         }));
 
-        idt.set_handler(1, make_idt_entry!(isr1, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Debug")) };
-            loop { } 
+        idt.set_handler(1, make_idt_entry!(isr1, |frame| {
+            exception(frame, "Debug", None)
         }));
 
         idt.set_handler(2, make_idt_entry!(isr2, {
@@ -26,35 +29,30 @@ lazy_static! {
             loop { } 
         }));
 
-        idt.set_handler(3, make_idt_entry!(isr3, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Breakpoint")) };
-            loop { } 
+        idt.set_handler(3, make_idt_entry!(isr3, |frame| {
+            exception(frame, "Breakpoint", None)
         }));
 
-        idt.set_handler(4, make_idt_entry!(isr4, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Overflow")) };
-            loop { } 
+        idt.set_handler(4, make_idt_entry!(isr4, |frame| {
+            exception(frame, "Overflow", None)
         }));
 
-        idt.set_handler(5, make_idt_entry!(isr5, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Bound Range Exceeded")) };
-            loop { } 
+        idt.set_handler(5, make_idt_entry!(isr5, |frame| {
+            exception(frame, "Bound Range Exceeded", None)
         }));
 
-        idt.set_handler(6, make_idt_entry!(isr6, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Invalid Opcode")) };
-            loop { } 
+        idt.set_handler(6, make_idt_entry!(isr6, |frame| {
+            exception(frame, "Invalid Opcode", None)
         }));
 
-        idt.set_handler(7, make_idt_entry!(isr7, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Device Not Available")) };
-            loop { } 
+        idt.set_handler(7, make_idt_entry!(isr7, |frame| {
+            exception(frame, "Device Not Available", None)
         }));
 
-        idt.set_handler(8, make_idt_entry!(isr8, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Double Fault")) };
-            loop { } 
-        }));
+        idt.set_handler(8, make_idt_entry_with_error_code!(isr8, |frame, error_code| {
+            // runs on its own stack, so that a kernel stack overflow ends up here
+            exception(frame, "Double Fault", Some(error_code))
+        })).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16 + 1);
 
         idt.set_handler(9, make_idt_entry!(isr9, {
             // do nothing for now
@@ -62,29 +60,50 @@ lazy_static! {
             unsafe { irq::enable(); }
         }));
 
-        idt.set_handler(10, make_idt_entry!(isr10, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Invalid TSS")) };
-            loop { } 
+        idt.set_handler(10, make_idt_entry_with_error_code!(isr10, |frame, error_code| {
+            exception(frame, "Invalid TSS", Some(error_code))
         }));
 
-        idt.set_handler(11, make_idt_entry!(isr11, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Segment Not Present")) };
-            loop { } 
+        idt.set_handler(11, make_idt_entry_with_error_code!(isr11, |frame, error_code| {
+            exception(frame, "Segment Not Present", Some(error_code))
         }));
 
-        idt.set_handler(12, make_idt_entry!(isr12, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Stack-Segment Fault")) };
-            loop { } 
+        idt.set_handler(12, make_idt_entry_with_error_code!(isr12, |frame, error_code| {
+            exception(frame, "Stack-Segment Fault", Some(error_code))
         }));
 
-        idt.set_handler(13, make_idt_entry!(isr13, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: General Protection Fault")) };
-            loop { } 
+        idt.set_handler(13, make_idt_entry_with_error_code!(isr13, |frame, error_code| {
+            exception(frame, "General Protection Fault", Some(error_code))
         }));
 
-        idt.set_handler(14, make_idt_entry!(isr14, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: PAGE FAULT")) };
-            loop { } 
+        idt.set_handler(14, make_idt_entry_with_error_code!(isr14, |frame, error_code| {
+            let address = unsafe { controlregs::cr2() } as usize;
+            let result = if address < memory::USER_END {
+                task::handle_user_page_fault(address, error_code, frame.interrupts_enabled())
+            } else {
+                memory::handle_page_fault(address, error_code)
+            };
+            if let Err(error) = result {
+                // a lock that the fault handler needs is held by the faulting code itself
+                if error == memory::PageFaultError::Locked {
+                    panic!("page fault at {:#x} with a memory lock held, error code {:#x}",
+                           address,
+                           error_code);
+                }
+                if frame.from_user_mode() {
+                    klog!(Warn, "page fault at {:#x} in user mode: {:?}, killing thread {}",
+                          address, error, task::current());
+                    process::exit(ExitStatus::Killed(process::SIGSEGV));
+                }
+                // the kernel maps user buffers before it accesses them, so this is a kernel bug
+                // even for user addresses
+                unsafe {
+                    vga::print_error(format_args!("EXCEPTION: PAGE FAULT at {:#x}: {:?}, \
+                                                   error code {:#x}",
+                                                  address, error, error_code))
+                };
+                loop { }
+            }
         }));
 
         idt.set_handler(15, make_idt_entry!(isr15, {
@@ -93,14 +112,12 @@ lazy_static! {
             unsafe { irq::enable(); } 
         }));
 
-        idt.set_handler(16, make_idt_entry!(isr16, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: x87 Floating-Point Exception")) };
-            loop { } 
+        idt.set_handler(16, make_idt_entry!(isr16, |frame| {
+            exception(frame, "x87 Floating-Point Exception", None)
         }));
 
-        idt.set_handler(17, make_idt_entry!(isr17, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Alignment Check")) };
-            loop { } 
+        idt.set_handler(17, make_idt_entry_with_error_code!(isr17, |frame, error_code| {
+            exception(frame, "Alignment Check", Some(error_code))
         }));
 
         idt.set_handler(18, make_idt_entry!(isr18, {
@@ -108,14 +125,12 @@ lazy_static! {
             loop { } 
         }));
 
-        idt.set_handler(19, make_idt_entry!(isr19, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: SIMD Floating-Point Exception")) };
-            loop { } 
+        idt.set_handler(19, make_idt_entry!(isr19, |frame| {
+            exception(frame, "SIMD Floating-Point Exception", None)
         }));
 
-        idt.set_handler(20, make_idt_entry!(isr20, {
-            unsafe { vga::print_error(format_args!("EXCEPTION: Virtualization Exception")) };
-            loop { } 
+        idt.set_handler(20, make_idt_entry!(isr20, |frame| {
+            exception(frame, "Virtualization Exception", None)
         }));
 
         idt.set_handler(21, make_idt_entry!(isr21, {
@@ -172,9 +187,8 @@ lazy_static! {
             unsafe { irq::enable(); } 
         }));
 
-        idt.set_handler(30, make_idt_entry!(isr30, {
-           unsafe { vga::print_error(format_args!("EXCEPTION: Security Exception")) };
-            loop { } 
+        idt.set_handler(30, make_idt_entry_with_error_code!(isr30, |frame, error_code| {
+            exception(frame, "Security Exception", Some(error_code))
         }));
 
         idt.set_handler(31, make_idt_entry!(isr31, {
@@ -184,9 +198,9 @@ lazy_static! {
         }));
 
         idt.set_handler(32, make_idt_entry!(isr32, {
-            // timer, do nothing for now
+            // timer, the current thread might be switched out, so the EOI has to come first
             pic::eoi_for(32);
-            unsafe { irq::enable(); } 
+            task::tick();
         }));
 
         idt.set_handler(33, make_idt_entry!(isr33, {
@@ -198,10 +212,62 @@ lazy_static! {
             unsafe { irq::enable(); } 
         }));
 
+        // system calls, like `syscall` but easier to debug
+        idt.set_handler(0x80, idt::Entry::new(segmentation::cs(), syscall::int80_entry))
+           .set_privilege_level(3);
+
         idt
     };
 }
 
 pub fn init() {
+	// the double fault stack is never freed
+	let double_fault_stack = memory::alloc_stack(4).expect("no memory for the double fault stack");
+	gdt::init(double_fault_stack.top());
+	mem::forget(double_fault_stack);
 	IDT.load();
+}
+
+/// Handles an exception that the interrupted code can't recover from. An exception in user mode
+/// only kills the running thread, as if the process got `SIGSEGV`. So does a fault of the return
+/// to user mode with a non-canonical instruction pointer.
+fn exception(frame: &idt::ExceptionStackFrame, name: &str, error_code: Option<u64>) {
+	if frame.from_user_mode() || syscall::is_user_return_fault(frame.instruction_pointer) {
+		klog!(Warn, "{} at {:#x} in user mode, killing thread {}",
+			  name, frame.instruction_pointer, task::current());
+		process::exit(ExitStatus::Killed(process::SIGSEGV));
+	}
+	unsafe {
+		match error_code {
+			Some(error_code) => {
+				vga::print_error(format_args!("EXCEPTION: {} at {:#x}, error code {:#x}",
+											  name, frame.instruction_pointer, error_code))
+			}
+			None => {
+				vga::print_error(format_args!("EXCEPTION: {} at {:#x}",
+											  name, frame.instruction_pointer))
+			}
+		}
+	};
+	loop { }
+}
+
+/// Returns true if maskable interrupts are enabled (RFLAGS.IF).
+pub fn enabled() -> bool {
+	let rflags: u64;
+	unsafe { asm!("pushfq; pop $0" : "=r"(rflags) ::: "volatile", "intel") };
+	rflags & (1 << 9) != 0
+}
+
+/// Runs `f` with interrupts disabled and restores the previous state afterwards.
+pub fn without_interrupts<F, R>(f: F) -> R
+	where F: FnOnce() -> R
+{
+	let enabled = enabled();
+	unsafe { irq::disable() };
+	let result = f();
+	if enabled {
+		unsafe { irq::enable() };
+	}
+	result
 }
\ No newline at end of file
@@ -1,15 +1,44 @@
-use spin::Mutex;
+use sync::{IrqSpinlock, WaitQueue};
+use sync::lock_order;
+use cmdline::KeyboardLayout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-static KBDUS: [u8; 59] = *b"??1234567890-=??qwertyuiop[]\n?asdfghjkl;'`?\\zxcvbnm,./?*? ?";
-static KBDUS_SHIFT: [u8; 59] = *b"??!@#$%^&*()_+??QWERTYUIOP{}\n?ASDFGHJKL:\"~?|ZXCVBNM<>??*? ?";
+// The characters of the keys of a layout, without and with shift, indexed by scancode. Keys that
+// don't produce an ASCII character are `?`.
+struct Layout {
+	normal: [u8; 59],
+	shift: [u8; 59],
+}
+
+// Indexed by `KeyboardLayout`
+static LAYOUTS: [Layout; 2] = [Layout {
+	normal: *b"??1234567890-=??qwertyuiop[]\n?asdfghjkl;'`?\\zxcvbnm,./?*? ?",
+	shift: *b"??!@#$%^&*()_+??QWERTYUIOP{}\n?ASDFGHJKL:\"~?|ZXCVBNM<>??*? ?",
+}, Layout {
+	normal: *b"??1234567890?'??qwertzuiop?+\n?asdfghjkl??^?#yxcvbnm,.-?*? ?",
+	shift: *b"??!\"?$%&/()=?`??QWERTZUIOP?*\n?ASDFGHJKL????'YXCVBNM;:_?*? ?",
+}];
+
+// The layout in use, set by `init` from the `keyboard` command line parameter
+static LAYOUT: AtomicUsize = AtomicUsize::new(KeyboardLayout::Us as usize);
 
-// State of Modifier keys
-pub static STATE: Mutex<Modifiers> = Mutex::new(Modifiers {
+// State of Modifier keys, updated by the keyboard interrupt
+pub static STATE: IrqSpinlock<Modifiers> = IrqSpinlock::with_level(Modifiers {
 	shift: false,
 	ctrl: false,
 	alt: false,
 	caps: false,
-});
+}, "keyboard::STATE", lock_order::KEYBOARD);
+
+// Characters that were typed but not read yet, filled by the keyboard interrupt
+static INPUT: IrqSpinlock<InputBuffer> = IrqSpinlock::with_level(InputBuffer {
+	bytes: [0; INPUT_SIZE],
+	start: 0,
+	len: 0,
+}, "keyboard::INPUT", lock_order::KEYBOARD);
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+const INPUT_SIZE: usize = 256;
 
 pub struct Modifiers {
     shift: bool,
@@ -38,13 +67,64 @@ pub struct Keyboard;
 
 impl Keyboard {
 	pub fn handle_keys(&self, scancode: usize) {
-		if scancode <= 59 {
-			let state = STATE.lock();
-			if state.shift ^ state.caps {
-				kprint!("{}", KBDUS_SHIFT[scancode] as char);
-			} else {
-				kprint!("{}", KBDUS[scancode] as char);
-			}
+		let layout = &LAYOUTS[LAYOUT.load(Ordering::Relaxed)];
+		if scancode < layout.normal.len() {
+			let byte = {
+				let state = STATE.lock();
+				if state.shift ^ state.caps {
+					layout.shift[scancode]
+				} else {
+					layout.normal[scancode]
+				}
+			};
+			INPUT.lock().push(byte);
+			INPUT_READY.wake_all();
+			kprint!("{}", byte as char);
 		}
 	}
 }
+
+// A ring buffer of typed characters. When it is full, new characters are dropped.
+struct InputBuffer {
+	bytes: [u8; INPUT_SIZE],
+	start: usize,
+	len: usize,
+}
+
+impl InputBuffer {
+	fn push(&mut self, byte: u8) {
+		if self.len < INPUT_SIZE {
+			self.bytes[(self.start + self.len) % INPUT_SIZE] = byte;
+			self.len += 1;
+		}
+	}
+
+	fn pop_into(&mut self, buf: &mut [u8]) -> usize {
+		let count = if buf.len() < self.len { buf.len() } else { self.len };
+		for byte in buf[..count].iter_mut() {
+			*byte = self.bytes[self.start];
+			self.start = (self.start + 1) % INPUT_SIZE;
+		}
+		self.len -= count;
+		count
+	}
+}
+
+// Selects the layout that scancodes are translated with.
+pub fn init(layout: KeyboardLayout) {
+	LAYOUT.store(layout as usize, Ordering::SeqCst);
+}
+
+// Waits until characters were typed and copies up to `buf.len()` of them to `buf`. Returns the
+// number of characters read.
+pub fn read(buf: &mut [u8]) -> usize {
+	if buf.is_empty() {
+		return 0;
+	}
+	let mut count = 0;
+	INPUT_READY.wait_until(|| {
+		count = INPUT.lock().pop_into(buf);
+		count > 0
+	});
+	count
+}
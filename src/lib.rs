@@ -16,7 +16,13 @@ extern crate x86;
 extern crate once;
 extern crate bit_field;
 
-extern crate hole_list_allocator;
+#[cfg(feature = "hole_list_allocator")]
+extern crate hole_list_allocator as heap;
+#[cfg(feature = "slab_allocator")]
+extern crate slab_allocator as heap;
+#[cfg(feature = "bump_allocator")]
+extern crate bump_allocator as heap;
+extern crate arena;
 extern crate alloc;
 #[macro_use]
 extern crate collections;
@@ -24,15 +30,23 @@ extern crate cpuio;
 
 #[macro_use]
 mod vga;
+mod multiboot_tags;
+mod cmdline;
 mod memory;
+mod boot_modules;
+mod sync;
 
 mod interrupts;
+mod task;
+mod process;
+mod syscall;
+mod elf;
 mod pic;
 mod keyboard;
 
 #[no_mangle]
 pub extern fn print_memory_areas(multiboot_info_addr: usize) {
-	let boot_info = unsafe { multiboot2::load(multiboot_info_addr) };
+	let boot_info = unsafe { multiboot2::load(memory::KERNEL_OFFSET + multiboot_info_addr) };
 	let memory_map_tag = boot_info.memory_map_tag()
 		.expect("Memory map tag is required!");
 	kprintln!("Memory areas : ");
@@ -55,15 +69,17 @@ pub extern fn print_memory_areas(multiboot_info_addr: usize) {
 		.map(|s| s.addr)
 		.max()
 		.unwrap();
-	let multiboot_start = multiboot_info_addr;
+	let multiboot_start = boot_info.start_address();
 	let multiboot_end = multiboot_start + (boot_info.total_size as usize);
 	kprintln!("kernel start: 0x{:x}, kernel end: 0x{:x}",
 			 kernel_start, kernel_end);
 	kprintln!("multiboot_start: 0x{:x}, multiboot_end: 0x{:x}",
 			 multiboot_start, multiboot_end);
 	let mut frame_allocator = memory::AreaFrameAllocator::new(
-		kernel_start as usize, kernel_end as usize, multiboot_start,
-		multiboot_end, memory_map_tag.memory_areas());
+		memory::kernel_to_physical(kernel_start as usize),
+		memory::kernel_to_physical(kernel_end as usize),
+		memory::kernel_to_physical(multiboot_start),
+		memory::kernel_to_physical(multiboot_end), memory_map_tag.memory_areas());
 	for i in 0.. {
 		use memory::FrameAllocator;
 		if let None = frame_allocator.allocate_frame() {
@@ -75,18 +91,29 @@ pub extern fn print_memory_areas(multiboot_info_addr: usize) {
 
 #[no_mangle]
 pub extern fn rust_main(multiboot_info_address: usize) {
-	let boot_info = unsafe { multiboot2::load(multiboot_info_address) };
+	// the boot code passes the physical address, which is mapped in the higher half
+	let boot_info = unsafe { multiboot2::load(memory::KERNEL_OFFSET + multiboot_info_address) };
 	enable_nxe_bit();
+	enable_syscall_bit();
 	enable_write_protect_bit();
 	pic::remap_pic();
 	vga::initialize();
+	cmdline::init(boot_info);
+	let config = cmdline::config();
 	// set up guard page and map the heap pages
 	memory::init(boot_info);
+	heap::set_limit(config.heap_size);
+	boot_modules::init(boot_info);
+	keyboard::init(config.keyboard_layout);
+	klog!(Info, "{}", heap::stats());
 
 	// initialize our IDT
 	interrupts::init(); // laad
-	unsafe { x86::irq::enable(); }
-	loop { }
+	syscall::init();
+	task::init(config.scheduler);
+	process::init(config.init);
+	// the boot thread runs whenever no other thread is ready
+	task::idle();
 }
 
 fn enable_nxe_bit() {
@@ -99,6 +126,16 @@ fn enable_nxe_bit() {
 	}
 }
 
+fn enable_syscall_bit() {
+	use x86::msr::{IA32_EFER, rdmsr, wrmsr};
+
+	let sce_bit = 1 << 0;
+	unsafe {
+		let efer = rdmsr(IA32_EFER);
+		wrmsr(IA32_EFER, efer | sce_bit);
+	}
+}
+
 fn enable_write_protect_bit() {
 	use x86::controlregs::{cr0, cr0_write};
 
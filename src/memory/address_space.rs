@@ -0,0 +1,709 @@
+use memory::{PAGE_SIZE, Frame, FrameAllocator, BuddyAllocator, FRAME_ALLOCATOR,
+             TEMPORARY_PAGE_MAPPING};
+use memory::paging::{ActivePageTable, InactivePageTable, Mapper, Page, EntryFlags,
+                     VirtualAddress, PhysicalAddress, WRITABLE, USER_ACCESSIBLE, NO_EXECUTE,
+                     COPY_ON_WRITE};
+use collections::Vec;
+use core::{cmp, mem, ptr, slice};
+
+/// The start of the higher half, which belongs to the kernel and is shared by all address
+/// spaces.
+const HIGHER_HALF: VirtualAddress = 0xffff_8000_0000_0000;
+
+/// What the memory of a virtual memory area comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed memory from the frame allocator.
+    Anonymous,
+    /// The physical memory starting at the given address, e.g. the kernel image or a device.
+    Physical(PhysicalAddress),
+    /// The contents of a file, starting at `offset`.
+    File { file: usize, offset: usize },
+    /// Memory that the kernel maps and unmaps itself, like the temporary page.
+    Reserved,
+}
+
+/// A virtual memory area, i.e. the range `[start, end)` of page aligned virtual addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    pub flags: EntryFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn new(start: VirtualAddress,
+               end: VirtualAddress,
+               flags: EntryFlags,
+               backing: Backing)
+               -> Vma {
+        assert!(start % PAGE_SIZE == 0 && end % PAGE_SIZE == 0,
+                "VMA bounds must be page aligned");
+        assert!(start < end, "VMA must not be empty");
+        Vma {
+            start: start,
+            end: end,
+            flags: flags,
+            backing: backing,
+        }
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end
+    }
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The area overlaps an existing area.
+    Overlap,
+    /// There is no free virtual range of the requested size.
+    NoSpace,
+    /// The VMA list of the kernel address space is full.
+    TooManyAreas,
+    /// There is no area starting at the given address.
+    NotFound,
+}
+
+bitflags! {
+    /// The error code that the CPU pushes for a page fault.
+    pub flags PageFaultErrorCode: u64 {
+        const PROTECTION_VIOLATION = 1 << 0,
+        const CAUSED_BY_WRITE =      1 << 1,
+        const USER_MODE =            1 << 2,
+        const MALFORMED_TABLE =      1 << 3,
+        const INSTRUCTION_FETCH =    1 << 4,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// The address is not inside any area.
+    SegmentationFault,
+    /// The access is not allowed by the flags of the area.
+    AccessViolation,
+    /// The area is not backed on demand.
+    NotDemandPaged,
+    /// There are no free frames left.
+    OutOfMemory,
+    /// A lock that the handler needs is held by the faulting code, which is a kernel bug.
+    Locked,
+}
+
+/// Maximum number of VMAs in the kernel address space.
+const MAX_KERNEL_VMAS: usize = 32;
+
+/// A list of non-overlapping VMAs, sorted by start address.
+///
+/// The list of the kernel address space is used by the page fault handler, which must not
+/// allocate, so its memory is allocated up front for a fixed number of areas. The lists of user
+/// address spaces grow as needed.
+#[derive(Clone)]
+pub struct VmaList {
+    vmas: Vec<Vma>,
+    /// The maximum number of areas, or `None` if the list grows.
+    capacity: Option<usize>,
+}
+
+pub type VmaIter<'a> = slice::Iter<'a, Vma>;
+
+impl VmaList {
+    /// Creates a list that grows on the heap.
+    pub fn new() -> VmaList {
+        VmaList {
+            vmas: Vec::new(),
+            capacity: None,
+        }
+    }
+
+    /// Creates a list for at most `capacity` areas, which never allocates after its creation.
+    pub fn with_capacity(capacity: usize) -> VmaList {
+        VmaList {
+            vmas: Vec::with_capacity(capacity),
+            capacity: Some(capacity),
+        }
+    }
+
+    pub fn iter(&self) -> VmaIter {
+        self.vmas.iter()
+    }
+
+    pub fn find(&self, address: VirtualAddress) -> Option<&Vma> {
+        self.iter().find(|vma| vma.contains(address))
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        if self.iter().any(|other| other.overlaps(&vma)) {
+            return Err(VmaError::Overlap);
+        }
+        if self.capacity == Some(self.vmas.len()) {
+            return Err(VmaError::TooManyAreas);
+        }
+
+        let index = self.iter().take_while(|other| other.start < vma.start).count();
+        self.vmas.insert(index, vma);
+        Ok(())
+    }
+
+    /// Removes the area that starts at `start`.
+    pub fn remove(&mut self, start: VirtualAddress) -> Result<Vma, VmaError> {
+        match self.iter().position(|vma| vma.start == start) {
+            Some(index) => Ok(self.vmas.remove(index)),
+            None => Err(VmaError::NotFound),
+        }
+    }
+
+    /// Returns the lowest start address of a free range of `size` bytes in `[start, end)`.
+    pub fn find_free(&self,
+                     size: usize,
+                     start: VirtualAddress,
+                     end: VirtualAddress)
+                     -> Option<VirtualAddress> {
+        let mut candidate = start;
+        for vma in self.iter() {
+            if vma.end <= candidate {
+                continue;
+            }
+            if vma.start >= candidate + size {
+                break;
+            }
+            candidate = vma.end;
+        }
+        if candidate + size <= end {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+}
+
+/// The page table of an address space.
+pub enum PageTable {
+    /// The currently loaded table.
+    Active(ActivePageTable),
+    Inactive(InactivePageTable),
+}
+
+/// A page table together with the virtual memory areas that are in use in it.
+pub struct AddressSpace {
+    table: PageTable,
+    vmas: VmaList,
+    /// Free virtual ranges are allocated from `[free_start, free_end)`.
+    free_start: VirtualAddress,
+    free_end: VirtualAddress,
+}
+
+impl AddressSpace {
+    /// Creates an address space without any areas. `allocate_vma` hands out ranges in
+    /// `[free_start, free_end)`.
+    pub fn new(table: PageTable,
+               free_start: VirtualAddress,
+               free_end: VirtualAddress)
+               -> AddressSpace {
+        // the kernel address space owns the active table
+        let vmas = match table {
+            PageTable::Active(_) => VmaList::with_capacity(MAX_KERNEL_VMAS),
+            PageTable::Inactive(_) => VmaList::new(),
+        };
+        AddressSpace {
+            table: table,
+            vmas: vmas,
+            free_start: free_start,
+            free_end: free_end,
+        }
+    }
+
+    pub fn table(&self) -> &PageTable {
+        &self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut PageTable {
+        &mut self.table
+    }
+
+    /// Returns the physical address of the P4 table, which is loaded into CR3 to switch to this
+    /// address space.
+    pub fn page_table_address(&self) -> PhysicalAddress {
+        match self.table {
+            // only the kernel address space owns the active table
+            PageTable::Active(_) => super::kernel_page_table(),
+            PageTable::Inactive(ref table) => table.p4_address(),
+        }
+    }
+
+    pub fn vmas(&self) -> VmaIter {
+        self.vmas.iter()
+    }
+
+    /// Returns the area that contains `address`.
+    pub fn find_vma(&self, address: VirtualAddress) -> Option<&Vma> {
+        self.vmas.find(address)
+    }
+
+    /// Returns true if `[start, start + len)` lies in user accessible areas, which are writable
+    /// if `write` is set. The pages don't have to be mapped yet.
+    pub fn is_user_accessible(&self, start: VirtualAddress, len: usize, write: bool) -> bool {
+        let end = match start.checked_add(len) {
+            Some(end) if end <= HIGHER_HALF => end,
+            _ => return false,
+        };
+        let mut address = start;
+        while address < end {
+            let vma = match self.find_vma(address) {
+                Some(vma) => vma,
+                None => return false,
+            };
+            if !vma.flags.contains(USER_ACCESSIBLE) || (write && !vma.flags.contains(WRITABLE)) {
+                return false;
+            }
+            address = vma.end;
+        }
+        true
+    }
+
+    /// Adds an area at a fixed address.
+    pub fn insert_vma(&mut self, vma: Vma) -> Result<(), VmaError> {
+        self.vmas.insert(vma)
+    }
+
+    /// Adds an area of `size` bytes at the lowest free address.
+    pub fn allocate_vma(&mut self,
+                        size: usize,
+                        flags: EntryFlags,
+                        backing: Backing)
+                        -> Result<Vma, VmaError> {
+        let size = (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        let start = match self.vmas.find_free(size, self.free_start, self.free_end) {
+            Some(start) => start,
+            None => return Err(VmaError::NoSpace),
+        };
+        let vma = Vma::new(start, start + size, flags, backing);
+        try!(self.vmas.insert(vma));
+        Ok(vma)
+    }
+
+    /// Removes the area that starts at `start`. The pages of the area are not unmapped.
+    pub fn remove_vma(&mut self, start: VirtualAddress) -> Result<Vma, VmaError> {
+        self.vmas.remove(start)
+    }
+
+    /// Removes the area that starts at `start` and unmaps its pages. Frames of anonymous areas
+    /// are freed.
+    pub fn unmap_vma(&mut self, start: VirtualAddress) -> Result<Vma, VmaError> {
+        let vma = try!(self.vmas.remove(start));
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = &mut *allocator;
+        self.with_mapper(|mapper| unmap_pages(&vma, mapper, allocator));
+        Ok(vma)
+    }
+
+    /// Executes `f` with a mapper for the page table of this address space.
+    pub fn with_mapper<F>(&mut self, f: F)
+        where F: FnOnce(&mut Mapper)
+    {
+        match self.table {
+            PageTable::Active(ref mut table) => f(&mut **table),
+            PageTable::Inactive(ref mut table) => {
+                let mut temporary_page = TEMPORARY_PAGE_MAPPING.lock();
+                let temporary_page = temporary_page.as_mut().expect("memory is not initialized");
+                let mut active_table = unsafe { ActivePageTable::new() };
+                active_table.with(table, temporary_page, f);
+            }
+        }
+    }
+
+    /// Removes all areas of the lower half and frees their frames and page tables, e.g. when the
+    /// process exits. Only the P4 table is left until the address space is dropped. It must not
+    /// be loaded.
+    pub fn unmap_lower_half(&mut self) {
+        match self.table {
+            PageTable::Active(_) => panic!("the kernel address space has no lower half"),
+            PageTable::Inactive(ref table) => {
+                assert!(!table.is_loaded(), "unmapping the loaded address space")
+            }
+        }
+
+        let vmas = mem::replace(&mut self.vmas, VmaList::new());
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = &mut *allocator;
+        self.with_mapper(|mapper| {
+            for vma in vmas.iter().filter(|vma| vma.start < HIGHER_HALF) {
+                unmap_pages(vma, mapper, allocator);
+            }
+        });
+    }
+
+    /// Creates a copy of the lower half of this address space. Writable pages are shared
+    /// copy-on-write: both address spaces map them read-only with `COPY_ON_WRITE` set, and the
+    /// first write to such a page copies it.
+    ///
+    /// The heap may need frames to grow and `with_mapper` locks the temporary page, so the lists
+    /// of mappings are allocated outside of `with_mapper` and before `FRAME_ALLOCATOR` is
+    /// locked. Only the existing page tables are walked, so large areas that are mostly unmapped
+    /// are cheap.
+    pub fn clone_cow(&mut self) -> AddressSpace {
+        let vmas = self.vmas.clone();
+        let runs = {
+            let user_vmas = || vmas.iter().filter(|vma| vma.start < HIGHER_HALF);
+            let vma_pages = |vma: &Vma| {
+                Page::range_inclusive(Page::containing_address(vma.start),
+                                      Page::containing_address(vma.end - 1))
+            };
+
+            let mut run_count = 0;
+            self.with_mapper(|mapper| {
+                for vma in user_vmas() {
+                    run_count += mapper.query_range(vma_pages(vma)).count();
+                }
+            });
+            let mut runs = Vec::with_capacity(run_count);
+            self.with_mapper(|mapper| {
+                for vma in user_vmas() {
+                    let vma_runs = mapper.query_range(vma_pages(vma));
+                    runs.extend(vma_runs.map(|run| (run, vma.backing)));
+                }
+            });
+            runs
+        };
+        let page_count: usize = runs.iter()
+                                    .map(|&(run, _)| {
+                                        (run.end.start_address() - run.start.start_address()) /
+                                        PAGE_SIZE + 1
+                                    })
+                                    .sum();
+        let mut mappings = Vec::with_capacity(page_count);
+
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = &mut *allocator;
+
+        // collect the mapped pages and write protect the writable ones
+        self.with_mapper(|mapper| {
+            for &(run, backing) in runs.iter() {
+                let shared = match backing {
+                    // device memory is really shared
+                    Backing::Physical(_) => true,
+                    _ => false,
+                };
+                let mut flags = run.flags;
+                if flags.contains(WRITABLE) && !shared {
+                    flags = (flags - WRITABLE) | COPY_ON_WRITE;
+                    mapper.protect_range(Page::range_inclusive(run.start, run.end),
+                                         flags,
+                                         allocator);
+                }
+                for page in Page::range_inclusive(run.start, run.end) {
+                    mappings.push((page, mapper.translate_page(page).unwrap(), flags, shared));
+                }
+            }
+        });
+
+        let mut clone = AddressSpace {
+            table: PageTable::Inactive(new_inactive_table(allocator)),
+            vmas: vmas,
+            free_start: self.free_start,
+            free_end: self.free_end,
+        };
+        clone.with_mapper(|mapper| {
+            for (page, frame, flags, shared) in mappings {
+                // physical memory doesn't belong to the frame allocator, `unmap_pages` doesn't
+                // free it either
+                if !shared {
+                    allocator.share_frame(&frame);
+                }
+                mapper.map_to(page, frame, flags, allocator);
+            }
+        });
+        clone
+    }
+
+    /// Handles a page fault at `address` in this address space, which must be active. Pages of
+    /// anonymous and physical areas are mapped on first access and copy-on-write pages are
+    /// copied on the first write.
+    pub fn handle_page_fault(&mut self,
+                             address: VirtualAddress,
+                             error_code: PageFaultErrorCode,
+                             allocator: &mut BuddyAllocator)
+                             -> Result<(), PageFaultError> {
+        let vma = match self.find_vma(address) {
+            Some(&vma) => vma,
+            None => return Err(PageFaultError::SegmentationFault),
+        };
+        let page = Page::containing_address(address);
+
+        let mut loaded_table;
+        let table = match self.table {
+            PageTable::Active(ref mut table) => table,
+            PageTable::Inactive(ref table) if table.is_loaded() => {
+                // the address space of the running user thread
+                loaded_table = unsafe { ActivePageTable::new() };
+                &mut loaded_table
+            }
+            PageTable::Inactive(_) => panic!("page fault in an inactive address space"),
+        };
+        if error_code.contains(PROTECTION_VIOLATION | CAUSED_BY_WRITE) &&
+           vma.flags.contains(WRITABLE) &&
+           table.page_flags(page).map_or(false, |flags| flags.contains(COPY_ON_WRITE)) {
+            return copy_on_write(page, table, allocator);
+        }
+
+        try!(check_access(&vma, error_code));
+        back_page(&vma, page, table, allocator)
+    }
+
+    /// Maps the pages of `[start, start + len)` like page faults by user accesses would, so that
+    /// the kernel can access the range without faulting while this address space stays locked.
+    /// The range must be user accessible, and writable if `write` is set. The address space must
+    /// be loaded.
+    pub fn fault_in_user(&mut self,
+                         start: VirtualAddress,
+                         len: usize,
+                         write: bool)
+                         -> Result<(), PageFaultError> {
+        if !self.is_user_accessible(start, len, write) {
+            return Err(PageFaultError::AccessViolation);
+        }
+        let access = if write { USER_MODE | CAUSED_BY_WRITE } else { USER_MODE };
+        let mut address = start;
+        while address < start + len {
+            let page = Page::containing_address(address);
+            let mut flags = None;
+            self.with_mapper(|mapper| flags = mapper.page_flags(page));
+            let error_code = match flags {
+                None => access,
+                Some(flags) if write && !flags.contains(WRITABLE) => access | PROTECTION_VIOLATION,
+                Some(_) => PageFaultErrorCode::empty(),
+            };
+            if !error_code.is_empty() {
+                let mut allocator = FRAME_ALLOCATOR.lock();
+                try!(self.handle_page_fault(address, error_code, &mut *allocator));
+            }
+            address = page.start_address() + PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to `address` in this address space, which doesn't have to be loaded. Pages
+    /// that are not mapped yet are backed like on a page fault, but regardless of the flags of
+    /// their area, so that e.g. read-only code can be loaded.
+    pub fn write(&mut self, address: VirtualAddress, data: &[u8]) -> Result<(), PageFaultError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let page_offset = (address + offset) % PAGE_SIZE;
+            let len = cmp::min(PAGE_SIZE - page_offset, data.len() - offset);
+            let frame = try!(self.populate_page(Page::containing_address(address + offset)));
+            let source = &data[offset..(offset + len)];
+            with_frame(frame, |start| unsafe {
+                ptr::copy_nonoverlapping(source.as_ptr(),
+                                         start.offset(page_offset as isize),
+                                         len);
+            });
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Returns the frame that `page` is mapped to. An unmapped page is backed first.
+    fn populate_page(&mut self, page: Page) -> Result<Frame, PageFaultError> {
+        let vma = match self.find_vma(page.start_address()) {
+            Some(&vma) => vma,
+            None => return Err(PageFaultError::SegmentationFault),
+        };
+        let mut mapped = None;
+        self.with_mapper(|mapper| {
+            mapped = mapper.page_flags(page)
+                           .map(|flags| (mapper.translate_page(page).unwrap(), flags));
+        });
+        if let Some((frame, flags)) = mapped {
+            // the frame is shared with another address space
+            assert!(!flags.contains(COPY_ON_WRITE), "write to a copy-on-write page");
+            return Ok(frame);
+        }
+
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let frame = match vma.backing {
+            Backing::Anonymous => {
+                let frame = match allocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => return Err(PageFaultError::OutOfMemory),
+                };
+                with_frame(frame.clone(), |start| unsafe { ptr::write_bytes(start, 0, PAGE_SIZE) });
+                frame
+            }
+            Backing::Physical(start) => {
+                Frame::containing_address(start + page.start_address() - vma.start)
+            }
+            Backing::File { .. } | Backing::Reserved => {
+                return Err(PageFaultError::NotDemandPaged)
+            }
+        };
+        let mapped_frame = frame.clone();
+        self.with_mapper(|mapper| mapper.map_to(page, mapped_frame, vma.flags, &mut *allocator));
+        Ok(frame)
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Unmaps the lower half and frees its frames and page tables. The kernel address space,
+    /// which owns the active table, is never dropped.
+    fn drop(&mut self) {
+        let p4_frame = match self.table {
+            PageTable::Active(_) => return,
+            PageTable::Inactive(ref table) => {
+                assert!(!table.is_loaded(), "dropping the loaded address space");
+                Frame::containing_address(table.p4_address())
+            }
+        };
+        self.unmap_lower_half();
+        FRAME_ALLOCATOR.lock().deallocate_frame(p4_frame);
+    }
+}
+
+/// Unmaps the mapped pages of `vma` and frees the frames that belong to the frame allocator.
+/// Only the existing page tables are walked.
+fn unmap_pages(vma: &Vma, mapper: &mut Mapper, allocator: &mut BuddyAllocator) {
+    let mut next = Page::containing_address(vma.start);
+    let last = Page::containing_address(vma.end - 1);
+    loop {
+        // the runs borrow the mapper, so they are searched again after each unmapped run
+        let run = match mapper.query_range(Page::range_inclusive(next, last)).next() {
+            Some(run) => run,
+            None => return,
+        };
+        for page in Page::range_inclusive(run.start, run.end) {
+            let frame = mapper.unmap(page, allocator);
+            match vma.backing {
+                // the memory doesn't belong to the frame allocator
+                Backing::Physical(_) => {}
+                _ => allocator.deallocate_frame(frame),
+            }
+        }
+        if run.end == last {
+            return;
+        }
+        next = Page::containing_address(run.end.start_address() + PAGE_SIZE);
+    }
+}
+
+/// Creates a page table that shares the kernel mappings of the active table.
+pub fn new_inactive_table<A>(allocator: &mut A) -> InactivePageTable
+    where A: FrameAllocator
+{
+    let frame = allocator.allocate_frame().expect("out of memory");
+    let mut temporary_page = TEMPORARY_PAGE_MAPPING.lock();
+    let temporary_page = temporary_page.as_mut().expect("memory is not initialized");
+    let mut active_table = unsafe { ActivePageTable::new() };
+
+    let mut table = InactivePageTable::new(frame, &mut active_table, temporary_page);
+    active_table.copy_kernel_entries(&mut table, temporary_page);
+    table
+}
+
+/// Executes `f` with `frame` mapped at the temporary page. `f` gets the start address.
+fn with_frame<F>(frame: Frame, f: F)
+    where F: FnOnce(*mut u8)
+{
+    let mut temporary_page = TEMPORARY_PAGE_MAPPING.lock();
+    let temporary_page = temporary_page.as_mut().expect("memory is not initialized");
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let start = temporary_page.map(frame, &mut active_table);
+    f(start as *mut u8);
+    temporary_page.unmap(&mut active_table);
+}
+
+/// Gives a copy-on-write page its own copy of the frame and makes it writable.
+fn copy_on_write(page: Page,
+                 active_table: &mut ActivePageTable,
+                 allocator: &mut BuddyAllocator)
+                 -> Result<(), PageFaultError> {
+    let flags = (active_table.page_flags(page).unwrap() - COPY_ON_WRITE) | WRITABLE;
+    let frame = active_table.translate_page(page).unwrap();
+
+    if allocator.reference_count(&frame) == 1 {
+        // all other address spaces already have their own copy
+        active_table.protect_range(Page::range_inclusive(page, page), flags, allocator);
+        return Ok(());
+    }
+
+    let copy = match allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return Err(PageFaultError::OutOfMemory),
+    };
+    {
+        let mut temporary_page = match TEMPORARY_PAGE_MAPPING.try_lock() {
+            Some(temporary_page) => temporary_page,
+            None => return Err(PageFaultError::Locked),
+        };
+        let temporary_page = temporary_page.as_mut().expect("memory is not initialized");
+        let copy_address = temporary_page.map(copy.clone(), active_table);
+        unsafe {
+            ptr::copy_nonoverlapping(page.start_address() as *const u8,
+                                     copy_address as *mut u8,
+                                     PAGE_SIZE);
+        }
+        temporary_page.unmap(active_table);
+    }
+
+    // drop this address space's reference to the shared frame
+    let shared = active_table.unmap_keep_tables(page, allocator);
+    allocator.deallocate_frame(shared);
+    active_table.map_to(page, copy, flags, allocator);
+    Ok(())
+}
+
+/// Checks that the faulting access is allowed by the flags of the area.
+fn check_access(vma: &Vma, error_code: PageFaultErrorCode) -> Result<(), PageFaultError> {
+    if error_code.contains(PROTECTION_VIOLATION) {
+        // the page is mapped, but the access is not allowed
+        return Err(PageFaultError::AccessViolation);
+    }
+    if error_code.contains(CAUSED_BY_WRITE) && !vma.flags.contains(WRITABLE) {
+        return Err(PageFaultError::AccessViolation);
+    }
+    if error_code.contains(USER_MODE) && !vma.flags.contains(USER_ACCESSIBLE) {
+        return Err(PageFaultError::AccessViolation);
+    }
+    if error_code.contains(INSTRUCTION_FETCH) && vma.flags.contains(NO_EXECUTE) {
+        return Err(PageFaultError::AccessViolation);
+    }
+    Ok(())
+}
+
+/// Maps `page` of `vma` to its backing memory.
+fn back_page<A>(vma: &Vma,
+                page: Page,
+                mapper: &mut Mapper,
+                allocator: &mut A)
+                -> Result<(), PageFaultError>
+    where A: FrameAllocator
+{
+    match vma.backing {
+        Backing::Anonymous => {
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return Err(PageFaultError::OutOfMemory),
+            };
+            // map the page writable first, so that it can be zeroed. The page tables of a user
+            // page have to be user accessible, too.
+            let flags = WRITABLE | NO_EXECUTE | (vma.flags & USER_ACCESSIBLE);
+            mapper.map_to(page, frame, flags, allocator);
+            unsafe { ptr::write_bytes(page.start_address() as *mut u8, 0, PAGE_SIZE) };
+            mapper.protect_range(Page::range_inclusive(page, page), vma.flags, allocator);
+            Ok(())
+        }
+        Backing::Physical(start) => {
+            let frame = Frame::containing_address(start + page.start_address() - vma.start);
+            mapper.map_to(page, frame, vma.flags, allocator);
+            Ok(())
+        }
+        Backing::File { .. } | Backing::Reserved => Err(PageFaultError::NotDemandPaged),
+    }
+}
@@ -0,0 +1,424 @@
+use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use multiboot2::MemoryAreaIter;
+use core::{mem, slice, u32};
+
+/// The largest supported block is `2^MAX_ORDER` frames (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+/// Marks the end of a free list.
+const NONE: u32 = u32::MAX;
+
+/// The `order` of a frame that doesn't start a free block.
+const NOT_FREE: u8 = 0xff;
+
+/// The allocator's information about a physical frame. The frame table has an entry for every
+/// frame up to the end of physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameInfo {
+    /// The neighbours of the free block that starts at this frame in the free list of its order.
+    next: u32,
+    prev: u32,
+    /// The order of the free block that starts at this frame, or `NOT_FREE`.
+    order: u8,
+    /// The number of references to an allocated frame besides the first, e.g. by copy-on-write
+    /// mappings.
+    shares: u32,
+}
+
+impl FrameInfo {
+    pub const fn new() -> FrameInfo {
+        FrameInfo {
+            next: NONE,
+            prev: NONE,
+            order: NOT_FREE,
+            shares: 0,
+        }
+    }
+}
+
+/// A doubly linked list of the free blocks of one order. The links are stored in the frame
+/// table, so that any number of blocks fit into the list and a block is removed in O(1).
+struct FreeList {
+    head: u32,
+    len: usize,
+}
+
+impl FreeList {
+    const fn new() -> FreeList {
+        FreeList {
+            head: NONE,
+            len: 0,
+        }
+    }
+}
+
+/// A binary buddy allocator for physical frames.
+///
+/// Free memory is kept as blocks of `2^order` frames whose start frame number is a multiple of
+/// `2^order`. Allocations split larger blocks and deallocations merge a block with its buddy
+/// (the block with the `order` bit of the start frame flipped) while the buddy is free.
+///
+/// The allocator is needed before the heap exists, so the free lists and the reference counts are
+/// kept in a frame table that is placed in physical memory by `set_frame_table`.
+pub struct BuddyAllocator {
+    free_lists: [FreeList; MAX_ORDER + 1],
+    frames: *mut FrameInfo,
+    frame_count: usize,
+}
+
+// the frame table is only accessed through the allocator
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    /// Creates an allocator without any free memory.
+    pub const fn empty() -> BuddyAllocator {
+        BuddyAllocator {
+            free_lists: [FreeList::new(), FreeList::new(), FreeList::new(), FreeList::new(),
+                         FreeList::new(), FreeList::new(), FreeList::new(), FreeList::new(),
+                         FreeList::new(), FreeList::new(), FreeList::new()],
+            frames: 0 as *mut FrameInfo,
+            frame_count: 0,
+        }
+    }
+
+    /// Returns the size of the frame table for the physical memory below `memory_end` in bytes.
+    pub fn frame_table_size(memory_end: usize) -> usize {
+        memory_end / PAGE_SIZE * mem::size_of::<FrameInfo>()
+    }
+
+    /// Sets the frame table, which must have an entry for every frame that is added to the
+    /// allocator. It must be called before any memory is added.
+    ///
+    /// Unsafe because the table must stay valid and must not be used otherwise while the
+    /// allocator exists.
+    pub unsafe fn set_frame_table(&mut self, table: &mut [FrameInfo]) {
+        assert!(self.frame_count == 0, "the frame table is already set");
+        assert!(table.len() < NONE as usize, "too many frames for the frame table");
+        for info in table.iter_mut() {
+            *info = FrameInfo::new();
+        }
+        self.frames = table.as_mut_ptr();
+        self.frame_count = table.len();
+    }
+
+    fn frames(&self) -> &[FrameInfo] {
+        unsafe { slice::from_raw_parts(self.frames, self.frame_count) }
+    }
+
+    fn frames_mut(&mut self) -> &mut [FrameInfo] {
+        unsafe { slice::from_raw_parts_mut(self.frames, self.frame_count) }
+    }
+
+    /// Returns true if `block` starts a free block of the given order.
+    fn is_free(&self, block: usize, order: usize) -> bool {
+        block < self.frame_count && self.frames()[block].order as usize == order
+    }
+
+    fn push(&mut self, order: usize, block: usize) {
+        assert!(block + (1 << order) <= self.frame_count,
+                "frame {:#x} is not in the frame table",
+                block);
+        let head = self.free_lists[order].head;
+        {
+            let frames = self.frames_mut();
+            frames[block] = FrameInfo {
+                next: head,
+                prev: NONE,
+                order: order as u8,
+                shares: 0,
+            };
+            if head != NONE {
+                frames[head as usize].prev = block as u32;
+            }
+        }
+        self.free_lists[order].head = block as u32;
+        self.free_lists[order].len += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        match self.free_lists[order].head {
+            NONE => None,
+            head => {
+                self.remove(order, head as usize);
+                Some(head as usize)
+            }
+        }
+    }
+
+    /// Removes the free `block` from the list of its order.
+    fn remove(&mut self, order: usize, block: usize) {
+        let FrameInfo { next, prev, .. } = self.frames()[block];
+        {
+            let frames = self.frames_mut();
+            if next != NONE {
+                frames[next as usize].prev = prev;
+            }
+            if prev != NONE {
+                frames[prev as usize].next = next;
+            }
+            frames[block] = FrameInfo::new();
+        }
+        if prev == NONE {
+            self.free_lists[order].head = next;
+        }
+        self.free_lists[order].len -= 1;
+    }
+
+    /// Adds the available multiboot memory areas, except the frames in the `reserved` physical
+    /// address ranges, e.g. the kernel, the multiboot information structure and the boot modules.
+    ///
+    /// The ends of the `reserved` ranges are _inclusive_ bounds.
+    pub fn add_memory_areas(&mut self, reserved: &[(usize, usize)], memory_areas: MemoryAreaIter) {
+        for area in memory_areas {
+            // only use frames that lie completely inside the area
+            let first = Frame::containing_address(area.base_addr as usize + PAGE_SIZE - 1);
+            let end = Frame::containing_address((area.base_addr + area.length) as usize);
+            self.add_range_excluding(first, end, reserved);
+        }
+    }
+
+    /// Adds the frames in `[start, end)` that don't contain an address of one of the inclusive
+    /// `reserved` ranges.
+    fn add_range_excluding(&mut self, start: Frame, end: Frame, reserved: &[(usize, usize)]) {
+        let reserved_frames = || {
+            reserved.iter().map(|&(s, e)| {
+                (Frame::containing_address(s).number, Frame::containing_address(e).number)
+            })
+        };
+        let mut next = start.number;
+        while next < end.number {
+            // skip reserved frames
+            let reserved_range = reserved_frames().find(|&(s, e)| next >= s && next <= e);
+            if let Some((_, reserved_end)) = reserved_range {
+                next = reserved_end + 1;
+                continue;
+            }
+            // the free run ends at the next reserved range or at `end`
+            let run_end = reserved_frames()
+                              .map(|(s, _)| s)
+                              .filter(|&s| s > next && s < end.number)
+                              .min()
+                              .unwrap_or(end.number);
+            self.add_range(Frame { number: next }, Frame { number: run_end });
+            next = run_end;
+        }
+    }
+
+    /// Adds the free frames `[start, end)` to the allocator, using the largest aligned blocks
+    /// possible.
+    pub fn add_range(&mut self, start: Frame, end: Frame) {
+        let mut next = start.number;
+        while next < end.number {
+            let mut order = MAX_ORDER;
+            while next % (1 << order) != 0 || next + (1 << order) > end.number {
+                order -= 1;
+            }
+            self.deallocate_frames(Frame { number: next }, order);
+            next += 1 << order;
+        }
+    }
+
+    /// Allocates `2^order` physically contiguous frames, aligned to `2^order` frames.
+    /// Returns the first frame of the block.
+    pub fn allocate_frames(&mut self, order: usize) -> Option<Frame> {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+
+        // find the smallest free block that is large enough
+        let mut current = order;
+        while current <= MAX_ORDER && self.free_lists[current].len == 0 {
+            current += 1;
+        }
+        if current > MAX_ORDER {
+            return None;
+        }
+        let block = self.pop(current).unwrap();
+
+        // split it and put the unused upper halves back
+        while current > order {
+            current -= 1;
+            self.push(current, block + (1 << current));
+        }
+        Some(Frame { number: block })
+    }
+
+    /// Frees a block of `2^order` frames that was returned by `allocate_frames`.
+    pub fn deallocate_frames(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} is too large", order);
+        assert!(frame.number % (1 << order) == 0,
+                "frame {:#x} is not aligned to order {}",
+                frame.number,
+                order);
+
+        let mut block = frame.number;
+        let mut order = order;
+        // merge with the buddy as long as it is free
+        while order < MAX_ORDER && self.is_free(block ^ (1 << order), order) {
+            self.remove(order, block ^ (1 << order));
+            block &= !(1 << order);
+            order += 1;
+        }
+        self.push(order, block);
+    }
+
+    /// Adds a reference to an allocated frame. The frame is only freed by `deallocate_frame`
+    /// when the last reference is dropped.
+    pub fn share_frame(&mut self, frame: &Frame) {
+        assert!(frame.number < self.frame_count,
+                "frame {:#x} doesn't belong to the allocator",
+                frame.number);
+        self.frames_mut()[frame.number].shares += 1;
+    }
+
+    /// Returns the number of references to an allocated frame.
+    pub fn reference_count(&self, frame: &Frame) -> usize {
+        if frame.number < self.frame_count {
+            self.frames()[frame.number].shares as usize + 1
+        } else {
+            1
+        }
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free_lists
+            .iter()
+            .enumerate()
+            .map(|(order, list)| list.len << order)
+            .sum()
+    }
+}
+
+impl FrameAllocator for BuddyAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        self.allocate_frames(0)
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if frame.number < self.frame_count && self.frames()[frame.number].shares > 0 {
+            self.frames_mut()[frame.number].shares -= 1;
+        } else {
+            self.deallocate_frames(frame, 0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{Frame, FrameAllocator, PAGE_SIZE};
+
+    fn frame(number: usize) -> Frame {
+        Frame { number: number }
+    }
+
+    fn new_allocator(table: &mut [FrameInfo]) -> BuddyAllocator {
+        let mut allocator = BuddyAllocator::empty();
+        unsafe { allocator.set_frame_table(table) };
+        allocator
+    }
+
+    #[test]
+    fn add_range_uses_aligned_blocks() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(3), frame(16));
+        // 3 | 4..8 | 8..16
+        assert_eq!(allocator.free_lists[0].len, 1);
+        assert_eq!(allocator.free_lists[2].len, 1);
+        assert_eq!(allocator.free_lists[3].len, 1);
+        assert_eq!(allocator.free_frames(), 13);
+    }
+
+    #[test]
+    fn allocate_splits_blocks() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(0), frame(16));
+        assert_eq!(allocator.allocate_frames(0), Some(frame(0)));
+        assert_eq!(allocator.allocate_frames(0), Some(frame(1)));
+        assert_eq!(allocator.allocate_frames(1), Some(frame(2)));
+        assert_eq!(allocator.allocate_frames(2), Some(frame(4)));
+        assert_eq!(allocator.allocate_frames(3), Some(frame(8)));
+        assert_eq!(allocator.allocate_frames(0), None);
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(1), frame(64));
+        let block = allocator.allocate_frames(4).unwrap();
+        assert_eq!(block.number % 16, 0);
+        assert_eq!(allocator.free_frames(), 63 - 16);
+    }
+
+    #[test]
+    fn deallocate_coalesces_buddies() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(0), frame(8));
+        let a = allocator.allocate_frames(0).unwrap();
+        let b = allocator.allocate_frames(0).unwrap();
+        let c = allocator.allocate_frames(1).unwrap();
+        allocator.deallocate_frames(b, 0);
+        allocator.deallocate_frames(a, 0);
+        allocator.deallocate_frames(c, 1);
+        assert_eq!(allocator.free_lists[3].len, 1);
+        assert_eq!(allocator.allocate_frames(3), Some(frame(0)));
+    }
+
+    #[test]
+    fn free_lists_are_not_limited() {
+        let mut table = [FrameInfo::new(); 4096];
+        let mut allocator = new_allocator(&mut table);
+        for number in 0..2048 {
+            allocator.add_range(frame(2 * number), frame(2 * number + 1));
+        }
+        assert_eq!(allocator.free_lists[0].len, 2048);
+        for number in 0..2048 {
+            allocator.deallocate_frames(frame(2 * number + 1), 0);
+        }
+        assert_eq!(allocator.free_lists[0].len, 0);
+        assert_eq!(allocator.free_lists[MAX_ORDER].len, 4);
+    }
+
+    #[test]
+    fn no_coalescing_across_gaps() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(0), frame(1));
+        allocator.add_range(frame(2), frame(4));
+        assert_eq!(allocator.free_lists[0].len, 1);
+        assert_eq!(allocator.free_lists[1].len, 1);
+        assert_eq!(allocator.allocate_frames(2), None);
+    }
+
+    #[test]
+    fn shared_frames_are_freed_by_the_last_reference() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        allocator.add_range(frame(0), frame(1));
+        let f = allocator.allocate_frame().unwrap();
+        allocator.share_frame(&f);
+        allocator.share_frame(&f);
+        assert_eq!(allocator.reference_count(&f), 3);
+        allocator.deallocate_frame(frame(0));
+        allocator.deallocate_frame(frame(0));
+        assert_eq!(allocator.reference_count(&f), 1);
+        assert_eq!(allocator.free_frames(), 0);
+        allocator.deallocate_frame(f);
+        assert_eq!(allocator.free_frames(), 1);
+    }
+
+    #[test]
+    fn reserved_ranges_are_skipped() {
+        let mut table = [FrameInfo::new(); 64];
+        let mut allocator = new_allocator(&mut table);
+        let reserved = [(4 * PAGE_SIZE, 5 * PAGE_SIZE), (10 * PAGE_SIZE, 11 * PAGE_SIZE + 1)];
+        allocator.add_range_excluding(frame(0), frame(16), &reserved);
+        assert_eq!(allocator.free_frames(), 12);
+        while let Some(f) = allocator.allocate_frames(0) {
+            assert!(f.number != 4 && f.number != 5 && f.number != 10 && f.number != 11);
+        }
+    }
+}
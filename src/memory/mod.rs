@@ -1,54 +1,420 @@
+pub use self::address_space::{AddressSpace, Backing, PageTable, Vma, VmaError, PageFaultError,
+                               new_inactive_table};
+use self::address_space::PageFaultErrorCode;
 pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::remap_the_kernel;
-use self::paging::PhysicalAddress;
-use multiboot2::BootInformation;
+pub use self::buddy_allocator::BuddyAllocator;
+use self::buddy_allocator::FrameInfo;
+pub use self::paging::{remap_the_kernel, EntryFlags};
+pub use self::stack_allocator::{Stack, alloc_stack, MAX_STACK_PAGES};
+use self::paging::{PhysicalAddress, TemporaryPage, EntryFlags, USER_ACCESSIBLE, WRITABLE,
+                   NO_EXECUTE};
+use multiboot2::{BootInformation, MemoryAreaIter};
+use boot_modules::{self, MAX_MODULES};
+use spin::Mutex;
+use sync::{IrqSpinlock, lock_order};
+use core::{cmp, slice};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+mod address_space;
 mod area_frame_allocator;
+mod buddy_allocator;
 mod paging;
+mod stack_allocator;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// The kernel is linked at this virtual address plus its physical address.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+
+/// Converts an address of the kernel image to its physical address. The boot code is linked at
+/// its physical address, so addresses below `KERNEL_OFFSET` are returned unchanged.
+pub fn kernel_to_physical(address: usize) -> PhysicalAddress {
+    if address >= KERNEL_OFFSET {
+        address - KERNEL_OFFSET
+    } else {
+        address
+    }
+}
+
+// The kernel uses the following P4 entries of the higher half:
+//
+// 256: all physical memory (only with the `physical_memory_offset` feature)
+// 508: areas that are handed out by `KERNEL_SPACE`
+// 509: the kernel heap
+// 510: the recursive page table mapping
+// 511: the kernel image
+
+/// `KERNEL_SPACE` hands out virtual ranges in `[KERNEL_AREAS_START, KERNEL_AREAS_END)`.
+pub const KERNEL_AREAS_START: usize = 0xffff_fe00_0000_0000;
+pub const KERNEL_AREAS_END: usize = 0xffff_fe80_0000_0000;
+
+/// The temporary page is the first page of the kernel areas.
+const TEMPORARY_PAGE: usize = KERNEL_AREAS_START;
+
+// User address spaces use the lower half, i.e. everything below `USER_END`. Programs are loaded
+// at low addresses, free ranges are handed out in `[USER_AREAS_START, USER_AREAS_END)` and the
+// stack ends at `USER_STACK_TOP`.
+//
+// The last page of the lower half is never mapped: a `syscall` at its end would return to the
+// non-canonical address 0x8000_0000_0000, on which `sysretq` faults in ring 0.
+pub const USER_END: usize = 0x0000_7fff_ffff_f000;
+pub const USER_AREAS_START: usize = 0x0000_1000_0000_0000;
+pub const USER_AREAS_END: usize = 0x0000_7000_0000_0000;
+pub const USER_STACK_TOP: usize = USER_END;
+
+/// The boot page tables map the physical memory below this address at `KERNEL_OFFSET`.
+const BOOT_MAPPING_END: PhysicalAddress = 0x4000_0000;
+
+/// The physical address of the kernel's P4 table. It is loaded when a kernel thread runs.
+static KERNEL_PAGE_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// The physical frame allocator. It is filled by `init`.
+///
+/// Interrupts are disabled while it is held, so its holder is never preempted and a page fault
+/// never has to wait for another thread to release it.
+pub static FRAME_ALLOCATOR: IrqSpinlock<BuddyAllocator> =
+    IrqSpinlock::with_level(BuddyAllocator::empty(),
+                            "memory::FRAME_ALLOCATOR",
+                            lock_order::UNORDERED);
+
+/// The kernel address space. It owns the active page table and is created by `init`.
+///
+/// Locks must be taken in the order `KERNEL_SPACE`, `FRAME_ALLOCATOR`, `TEMPORARY_PAGE_MAPPING`.
+pub static KERNEL_SPACE: Mutex<Option<AddressSpace>> = Mutex::new(None);
+
+/// The temporary page that is used to edit inactive page tables. Like `FRAME_ALLOCATOR`, it is
+/// never held by a preempted thread.
+pub static TEMPORARY_PAGE_MAPPING: IrqSpinlock<Option<TemporaryPage>> =
+    IrqSpinlock::with_level(None, "memory::TEMPORARY_PAGE_MAPPING", lock_order::UNORDERED);
+
 pub fn init(boot_info: &BootInformation) {
     assert_has_not_been_called!("memory::init must be called only once");
 
+    use self::paging::{Page, EntryFlags};
+
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
     let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
 
     let kernel_start = elf_sections_tag.sections()
                                        .filter(|s| s.is_allocated())
-                                       .map(|s| s.addr)
+                                       .map(|s| kernel_to_physical(s.addr as usize))
                                        .min()
                                        .unwrap();
     let kernel_end = elf_sections_tag.sections()
                                      .filter(|s| s.is_allocated())
-                                     .map(|s| s.addr + s.size)
+                                     .map(|s| kernel_to_physical((s.addr + s.size) as usize))
                                      .max()
                                      .unwrap();
 
-    kprintln!("kernel start: {:#x}, kernel end: {:#x}",
-             kernel_start,
-             kernel_end);
-    kprintln!("multiboot start: {:#x}, multiboot end: {:#x}",
-             boot_info.start_address(),
-             boot_info.end_address());
+    klog!(Debug, "kernel start: {:#x}, kernel end: {:#x}", kernel_start, kernel_end);
+    klog!(Debug,
+          "multiboot start: {:#x}, multiboot end: {:#x}",
+          boot_info.start_address(),
+          boot_info.end_address());
 
-    let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize,
-                                                      kernel_end as usize,
-                                                      boot_info.start_address(),
-                                                      boot_info.end_address(),
-                                                      memory_map_tag.memory_areas());
+    // the kernel, the multiboot information structure, the boot modules and the frame table
+    // stay in memory; `module_tags` returns at most `MAX_MODULES` modules
+    let mut reserved = [(0, 0); 3 + MAX_MODULES];
+    reserved[0] = (kernel_start, kernel_end);
+    reserved[1] = (kernel_to_physical(boot_info.start_address()),
+                   kernel_to_physical(boot_info.end_address()));
+    let mut reserved_count = 2;
+    for tag in boot_modules::module_tags(boot_info).filter(|tag| tag.end > tag.start) {
+        reserved[reserved_count] = (tag.start, tag.end - 1);
+        reserved_count += 1;
+    }
 
-    let mut active_table = paging::remap_the_kernel(&mut frame_allocator, boot_info);
+    // the frame table of the allocator is accessed through the boot mapping until the kernel
+    // is remapped
+    let memory_end = memory_map_tag.memory_areas()
+                                   .map(|area| (area.base_addr + area.length) as usize)
+                                   .max()
+                                   .unwrap();
+    let frame_table_size = align_up(BuddyAllocator::frame_table_size(memory_end));
+    let frame_table_start = find_free_range(frame_table_size,
+                                            BOOT_MAPPING_END,
+                                            &reserved[..reserved_count],
+                                            memory_map_tag.memory_areas())
+                                .expect("no memory for the frame table");
+    let frame_table_end = frame_table_start + frame_table_size;
+    reserved[reserved_count] = (frame_table_start, frame_table_end - 1);
+    reserved_count += 1;
+    klog!(Debug, "frame table: {:#x}..{:#x}", frame_table_start, frame_table_end);
 
-    use self::paging::Page;
-    use hole_list_allocator::{HEAP_START, HEAP_SIZE};
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe {
+        let frame_table = (KERNEL_OFFSET + frame_table_start) as *mut FrameInfo;
+        frame_allocator.set_frame_table(slice::from_raw_parts_mut(frame_table,
+                                                                  memory_end / PAGE_SIZE));
+    }
+    frame_allocator.add_memory_areas(&reserved[..reserved_count], memory_map_tag.memory_areas());
+    klog!(Info, "free frames: {}", frame_allocator.free_frames());
+
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(TEMPORARY_PAGE),
+                                                &mut *frame_allocator);
+    let mut active_table = paging::remap_the_kernel(&mut *frame_allocator,
+                                                    &mut temporary_page,
+                                                    boot_info,
+                                                    (frame_table_start, frame_table_end));
+    let kernel_page_table = unsafe { ::x86::controlregs::cr3() } as usize;
+    KERNEL_PAGE_TABLE.store(kernel_page_table, Ordering::SeqCst);
 
+    use heap::{HEAP_START, HEAP_SIZE, HEAP_WINDOW_SIZE};
+
+    // the P3 tables of the kernel are shared with other address spaces, so they must not change
+    for &address in [KERNEL_AREAS_START, HEAP_START].iter() {
+        active_table.create_p3_table(Page::containing_address(address), &mut *frame_allocator);
+    }
+
+    // map the initial heap, it maps more pages through `grow_heap` when it is full
     let heap_start_page = Page::containing_address(HEAP_START);
     let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    active_table.map_range(Page::range_inclusive(heap_start_page, heap_end_page),
+                           paging::WRITABLE | paging::NO_EXECUTE,
+                           &mut *frame_allocator);
+
+    // record the areas that are in use
+    let mut kernel_space = AddressSpace::new(PageTable::Active(active_table),
+                                             KERNEL_AREAS_START,
+                                             KERNEL_AREAS_END);
+    // the guard pages of the boot stack are left out, so that they are never mapped again
+    let (guard_start, guard_end) = boot_stack_guard();
+    for section in elf_sections_tag.sections() {
+        if !section.is_allocated() || section.start_address() < KERNEL_OFFSET {
+            continue;
+        }
+        let section_start = section.start_address();
+        let section_end = align_up(section.end_address());
+        let parts = [(section_start, cmp::min(section_end, guard_start)),
+                     (cmp::max(section_start, guard_end), section_end)];
+        for &(start, end) in parts.iter().filter(|&&(start, end)| start < end) {
+            let vma = Vma::new(start,
+                               end,
+                               EntryFlags::from_elf_section_flags(section),
+                               Backing::Physical(kernel_to_physical(start)));
+            kernel_space.insert_vma(vma).expect("kernel sections overlap");
+        }
+    }
+    let vga_buffer = Vma::new(KERNEL_OFFSET + 0xb8000,
+                              KERNEL_OFFSET + 0xb8000 + PAGE_SIZE,
+                              paging::WRITABLE,
+                              Backing::Physical(0xb8000));
+    let multiboot_start = align_down(boot_info.start_address());
+    let multiboot = Vma::new(multiboot_start,
+                             align_up(boot_info.end_address()),
+                             paging::PRESENT,
+                             Backing::Physical(kernel_to_physical(multiboot_start)));
+    // the heap maps its pages itself, so that it never faults while the frame allocator is
+    // locked
+    let frame_table = Vma::new(KERNEL_OFFSET + frame_table_start,
+                               KERNEL_OFFSET + frame_table_end,
+                               paging::WRITABLE | paging::NO_EXECUTE,
+                               Backing::Physical(frame_table_start));
+    let heap = Vma::new(HEAP_START,
+                        HEAP_START + HEAP_WINDOW_SIZE,
+                        paging::WRITABLE | paging::NO_EXECUTE,
+                        Backing::Reserved);
+    let temporary_page_area = Vma::new(TEMPORARY_PAGE,
+                                       TEMPORARY_PAGE + PAGE_SIZE,
+                                       paging::WRITABLE,
+                                       Backing::Reserved);
+    for &vma in [vga_buffer, multiboot, frame_table, heap, temporary_page_area].iter() {
+        kernel_space.insert_vma(vma).expect("kernel areas overlap");
+    }
+    let stacks = kernel_space.allocate_vma(stack_allocator::STACK_AREA_SIZE,
+                                           paging::WRITABLE | paging::NO_EXECUTE,
+                                           Backing::Reserved)
+                             .expect("no space for the kernel stacks");
+    stack_allocator::init(stacks.start);
+
+    // keep the lock order
+    drop(frame_allocator);
+    *KERNEL_SPACE.lock() = Some(kernel_space);
+    *TEMPORARY_PAGE_MAPPING.lock() = Some(temporary_page);
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+    ::heap::set_grow_handler(grow_heap);
+    set_heap_debug_handlers();
+}
+
+/// Lets the heap debugging find the end of the current stack, so that it records the callers of
+/// allocations without reading beyond the stack.
+#[cfg(feature = "heap_debug")]
+fn set_heap_debug_handlers() {
+    ::heap::set_stack_top_handler(stack_allocator::stack_top);
+}
+
+#[cfg(not(feature = "heap_debug"))]
+fn set_heap_debug_handlers() {}
+
+/// Maps `size` bytes of new heap memory at `start`. Returns false if there are not enough free
+/// frames.
+///
+/// The heap P3 table is shared by all address spaces, so the pages are mapped in the active table
+/// and become visible everywhere.
+fn grow_heap(start: usize, size: usize) -> bool {
+    use self::paging::{ActivePageTable, Page, WRITABLE, NO_EXECUTE};
+
+    // the frame allocator is only held with interrupts disabled, so it is locked only if the
+    // allocating code holds it itself. Waiting for it would never end, so the heap doesn't grow
+    // and the allocation fails.
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+    // the new page tables need frames, too
+    let pages = size / PAGE_SIZE;
+    if frame_allocator.free_frames() < pages + pages / 512 + 2 {
+        return false;
     }
+
+    let mut active_table = unsafe { ActivePageTable::new() };
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+    active_table.map_range(Page::range_inclusive(start_page, end_page),
+                           WRITABLE | NO_EXECUTE,
+                           &mut *frame_allocator);
+    true
+}
+
+/// Maps `size` bytes of physical memory at `start` read-only into the kernel address space and
+/// returns the virtual address of `start`. The region is never unmapped, so the frames must not
+/// belong to the frame allocator.
+///
+/// The pages are mapped right away, because a page fault while `KERNEL_SPACE` is locked can't
+/// be handled.
+pub fn map_physical_region(start: PhysicalAddress, size: usize) -> usize {
+    use self::paging::Page;
+
+    let offset = start % PAGE_SIZE;
+    let flags = paging::PRESENT | paging::NO_EXECUTE;
+    let mut kernel_space = KERNEL_SPACE.lock();
+    let kernel_space = kernel_space.as_mut().expect("memory is not initialized");
+    let vma = kernel_space.allocate_vma(offset + size, flags, Backing::Physical(start - offset))
+                          .expect("no space for a physical region");
+
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let pages = Page::range_inclusive(Page::containing_address(vma.start),
+                                      Page::containing_address(vma.end - 1));
+    kernel_space.with_mapper(|mapper| {
+        for page in pages {
+            let frame = Frame::containing_address(start - offset + page.start_address() -
+                                                  vma.start);
+            mapper.map_to(page, frame, flags, &mut *frame_allocator);
+        }
+    });
+    vma.start + offset
+}
+
+/// Handles a page fault at `address` in the kernel address space.
+pub fn handle_page_fault(address: usize, error_code: u64) -> Result<(), PageFaultError> {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    // the faulting code might hold one of the locks, so they are not waited for
+    let mut kernel_space = match KERNEL_SPACE.try_lock() {
+        Some(kernel_space) => kernel_space,
+        None => return Err(PageFaultError::Locked),
+    };
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return Err(PageFaultError::Locked),
+    };
+    match *kernel_space {
+        Some(ref mut kernel_space) => {
+            kernel_space.handle_page_fault(address, error_code, &mut *frame_allocator)
+        }
+        None => Err(PageFaultError::SegmentationFault),
+    }
+}
+
+/// Creates an empty user address space. It shares the kernel mappings of the higher half.
+pub fn new_user_space() -> AddressSpace {
+    let table = new_inactive_table(&mut *FRAME_ALLOCATOR.lock());
+    AddressSpace::new(PageTable::Inactive(table), USER_AREAS_START, USER_AREAS_END)
+}
+
+/// Returns the page flags of user memory. User memory is always readable.
+pub fn user_page_flags(writable: bool, executable: bool) -> EntryFlags {
+    let mut flags = USER_ACCESSIBLE;
+    if writable {
+        flags = flags | WRITABLE;
+    }
+    if !executable {
+        flags = flags | NO_EXECUTE;
+    }
+    flags
+}
+
+/// Handles a page fault at a lower half `address` in `space`, the address space of the running
+/// thread.
+pub fn handle_user_page_fault(space: &mut AddressSpace,
+                              address: usize,
+                              error_code: u64)
+                              -> Result<(), PageFaultError> {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    // the frame allocator is only held with interrupts disabled, so it is locked only if the
+    // faulting code holds it itself
+    let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+        Some(frame_allocator) => frame_allocator,
+        None => return Err(PageFaultError::Locked),
+    };
+    space.handle_page_fault(address, error_code, &mut *frame_allocator)
+}
+
+/// Returns the physical address of the kernel's P4 table.
+pub fn kernel_page_table() -> PhysicalAddress {
+    KERNEL_PAGE_TABLE.load(Ordering::SeqCst)
+}
+
+/// Loads the P4 table at `address` into CR3, unless it is already loaded. The higher half is the
+/// same in all tables, so the kernel keeps running.
+///
+/// Unsafe because the table must stay alive while it is loaded.
+pub unsafe fn load_page_table(address: PhysicalAddress) {
+    use x86::controlregs;
+
+    if controlregs::cr3() as usize != address {
+        controlregs::cr3_write(address as u64);
+    }
+}
+
+/// Returns the start of `size` bytes of page aligned, available memory below `limit` that don't
+/// overlap the inclusive `reserved` ranges.
+fn find_free_range(size: usize,
+                   limit: PhysicalAddress,
+                   reserved: &[(usize, usize)],
+                   memory_areas: MemoryAreaIter)
+                   -> Option<PhysicalAddress> {
+    for area in memory_areas {
+        let area_end = cmp::min((area.base_addr + area.length) as usize, limit);
+        let mut start = align_up(area.base_addr as usize);
+        while start + size <= area_end {
+            let end = start + size - 1;
+            match reserved.iter().find(|&&(s, e)| s <= end && e >= start) {
+                Some(&(_, reserved_end)) => start = align_up(reserved_end + 1),
+                None => return Some(start),
+            }
+        }
+    }
+    None
+}
+
+/// Returns the pages of the boot page tables, which `remap_the_kernel` unmaps to guard the boot
+/// stack.
+fn boot_stack_guard() -> (usize, usize) {
+    extern "C" {
+        static p4_table: u8;
+    }
+    let start = unsafe { &p4_table as *const u8 as usize };
+    (start, start + 3 * PAGE_SIZE)
+}
+
+fn align_down(address: usize) -> usize {
+    address & !(PAGE_SIZE - 1)
+}
+
+fn align_up(address: usize) -> usize {
+    align_down(address + PAGE_SIZE - 1)
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -41,6 +41,8 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // bits 9 to 11 are available to the kernel
+        const COPY_ON_WRITE =   1 << 9,
         const NO_EXECUTE =      1 << 63,
     }
 }
@@ -64,4 +66,23 @@ impl EntryFlags {
 
         flags
     }
+
+    /// Returns the flags of a user page that holds an ELF program segment with the program header
+    /// flags `p_flags`.
+    pub fn from_elf_program_flags(p_flags: u32) -> EntryFlags {
+        const PF_X: u32 = 1 << 0;
+        const PF_W: u32 = 1 << 1;
+
+        // segments are always readable
+        let mut flags = PRESENT | USER_ACCESSIBLE;
+
+        if p_flags & PF_W != 0 {
+            flags = flags | WRITABLE;
+        }
+        if p_flags & PF_X == 0 {
+            flags = flags | NO_EXECUTE;
+        }
+
+        flags
+    }
 }
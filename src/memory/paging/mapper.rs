@@ -1,7 +1,7 @@
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, PageIter, ENTRY_COUNT, RECURSIVE_ENTRY};
 use super::entry::*;
-use super::table::{self, Table, Level4};
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use super::table::{self, Table, Level4, HierarchicalLevel};
+use memory::{PAGE_SIZE, TEMPORARY_PAGE, Frame, FrameAllocator};
 use core::ptr::Unique;
 
 pub struct Mapper {
@@ -11,7 +11,12 @@ pub struct Mapper {
 #[allow(unused_variables)]
 impl Mapper {
     pub unsafe fn new() -> Mapper {
-        Mapper { p4: Unique::new(table::P4) }
+        Mapper::with_p4(table::P4)
+    }
+
+    /// Creates a mapper for the P4 table at the given virtual address.
+    pub unsafe fn with_p4(p4: *mut Table<Level4>) -> Mapper {
+        Mapper { p4: Unique::new(p4) }
     }
 
     pub fn p4(&self) -> &Table<Level4> {
@@ -69,14 +74,56 @@ impl Mapper {
     pub fn map_to<A>(&mut self, page: Page, frame: Frame, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
-        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_table_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_table_create(page.p2_index(), allocator);
+        // the tables above a user page must be user accessible, too
+        let table_flags = flags & USER_ACCESSIBLE;
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), table_flags, allocator);
+        let mut p2 = p3.next_table_create(page.p3_index(), table_flags, allocator);
+        let mut p1 = p2.next_table_create(page.p2_index(), table_flags, allocator);
 
         assert!(p1[page.p1_index()].is_unused());
         p1[page.p1_index()].set(frame, flags | PRESENT);
     }
 
+    /// Maps the 2MiB page starting at `page` to the 2MiB of memory starting at `frame`.
+    pub fn map_to_huge_2m<A>(&mut self,
+                             page: Page,
+                             frame: Frame,
+                             flags: EntryFlags,
+                             allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert!(page.number % ENTRY_COUNT == 0, "page must be 2MiB aligned");
+        assert!(frame.number % ENTRY_COUNT == 0, "frame must be 2MiB aligned");
+
+        let table_flags = flags & USER_ACCESSIBLE;
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), table_flags, allocator);
+        let mut p2 = p3.next_table_create(page.p3_index(), table_flags, allocator);
+
+        assert!(p2[page.p2_index()].is_unused());
+        p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+    }
+
+    /// Maps the 1GiB page starting at `page` to the 1GiB of memory starting at `frame`.
+    pub fn map_to_huge_1g<A>(&mut self,
+                             page: Page,
+                             frame: Frame,
+                             flags: EntryFlags,
+                             allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert!(super::huge_1g_supported(), "1GiB pages are not supported by the CPU");
+        assert!(page.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "page must be 1GiB aligned");
+        assert!(frame.number % (ENTRY_COUNT * ENTRY_COUNT) == 0,
+                "frame must be 1GiB aligned");
+
+        let table_flags = flags & USER_ACCESSIBLE;
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), table_flags, allocator);
+
+        assert!(p3[page.p3_index()].is_unused());
+        p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+    }
+
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
         where A: FrameAllocator
     {
@@ -91,20 +138,286 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
-    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
+    /// Unmaps the given page and frees the P1, P2 and P3 tables that become empty.
+    ///
+    /// The frame the page pointed to is returned and not freed, because it does not necessarily
+    /// belong to the allocator.
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator
+    {
+        let frame = self.unmap_keep_tables(page, allocator);
+        self.free_empty_tables(page, allocator);
+        frame
+    }
+
+    /// Unmaps the given page, but keeps the page tables even if they become empty.
+    pub fn unmap_keep_tables<A>(&mut self, page: Page, allocator: &mut A) -> Frame
         where A: FrameAllocator
     {
         assert!(self.translate(page.start_address()).is_some());
 
+        // a huge page is split so that only `page` gets unmapped
+        self.split_huge_pages(page, allocator);
+
         let p1 = self.p4_mut()
                      .next_table_mut(page.p4_index())
                      .and_then(|p3| p3.next_table_mut(page.p3_index()))
                      .and_then(|p2| p2.next_table_mut(page.p2_index()))
-                     .expect("mapping code does not support huge pages");
+                     .expect("page has no P1 table");
         let frame = p1[page.p1_index()].pointed_frame().unwrap();
         p1[page.p1_index()].set_unused();
         unsafe { ::x86::tlb::flush(page.start_address()) };
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+        frame
+    }
+
+    /// Maps every page in `range` to a newly allocated frame.
+    pub fn map_range<A>(&mut self, range: PageIter, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for page in range {
+            self.map(page, flags, allocator);
+        }
+    }
+
+    /// Unmaps every page in `range` and frees the frames. This is the counterpart of `map_range`.
+    pub fn unmap_range<A>(&mut self, range: PageIter, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for page in range {
+            let frame = self.unmap(page, allocator);
+            allocator.deallocate_frame(frame);
+        }
+    }
+
+    /// Replaces the flags of every page in `range` with `flags`, keeping the mapped frames.
+    /// Huge pages that overlap the range are split first.
+    pub fn protect_range<A>(&mut self, range: PageIter, flags: EntryFlags, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for page in range {
+            self.split_huge_pages(page, allocator);
+
+            let p1 = self.p4_mut()
+                         .next_table_mut(page.p4_index())
+                         .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                         .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                         .expect("page is not mapped");
+            let frame = p1[page.p1_index()].pointed_frame().expect("page is not mapped");
+            p1[page.p1_index()].set(frame, flags | PRESENT);
+            unsafe { ::x86::tlb::flush(page.start_address()) };
+        }
+    }
+
+    /// Returns the runs of consecutive mapped pages in `range` that have the same flags.
+    /// Unmapped pages are skipped.
+    pub fn query_range(&self, range: PageIter) -> MappingRuns {
+        MappingRuns {
+            mapper: self,
+            pages: range,
+        }
+    }
+
+    /// Returns the flags of the given page, ignoring the accessed, dirty and huge page bits.
+    pub fn page_flags(&self, page: Page) -> Option<EntryFlags> {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+        let flags = if p3[page.p3_index()].flags().contains(PRESENT | HUGE_PAGE) {
+            Some(p3[page.p3_index()].flags())
+        } else {
+            p3.next_table(page.p3_index()).and_then(|p2| {
+                if p2[page.p2_index()].flags().contains(PRESENT | HUGE_PAGE) {
+                    Some(p2[page.p2_index()].flags())
+                } else {
+                    p2.next_table(page.p2_index())
+                      .map(|p1| p1[page.p1_index()].flags())
+                      .and_then(|flags| if flags.contains(PRESENT) { Some(flags) } else { None })
+                }
+            })
+        };
+        flags.map(|flags| flags - ACCESSED - DIRTY - HUGE_PAGE)
+    }
+
+    /// Returns the number of pages from `page` on that are unmapped because a table on their
+    /// path doesn't exist. Returns 1 if `page` has all its tables.
+    fn unmapped_pages(&self, page: Page) -> usize {
+        let pages_per_p1 = ENTRY_COUNT;
+        let pages_per_p2 = pages_per_p1 * ENTRY_COUNT;
+        let pages_per_p3 = pages_per_p2 * ENTRY_COUNT;
+
+        let pages_per_table = match self.p4().next_table(page.p4_index()) {
+            None => pages_per_p3,
+            Some(p3) => {
+                match p3.next_table(page.p3_index()) {
+                    None => pages_per_p2,
+                    Some(p2) if p2.next_table(page.p2_index()).is_none() => pages_per_p1,
+                    Some(_) => 1,
+                }
+            }
+        };
+        pages_per_table - page.number % pages_per_table
+    }
+
+    /// Frees the P1, P2 and P3 tables on the path to `page` that have no used entries anymore.
+    /// A table is only freed if the table below it was freed.
+    fn free_empty_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        // the recursive entry points to the P4 table itself, which must never be freed
+        if page.p4_index() == RECURSIVE_ENTRY {
+            return;
+        }
+        // the temporary page has no frames left for new tables, so its tables are kept
+        let temporary_page = Page::containing_address(TEMPORARY_PAGE);
+        if page.p4_index() == temporary_page.p4_index() &&
+           page.p3_index() == temporary_page.p3_index() &&
+           page.p2_index() == temporary_page.p2_index() {
+            return;
+        }
+
+        let p1_freed = self.p4_mut()
+                           .next_table_mut(page.p4_index())
+                           .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                           .map_or(false, |p2| {
+                               p2.free_next_table_if_empty(page.p2_index(), allocator)
+                           });
+        if !p1_freed {
+            return;
+        }
+
+        let p2_freed = self.p4_mut()
+                           .next_table_mut(page.p4_index())
+                           .map_or(false, |p3| {
+                               p3.free_next_table_if_empty(page.p3_index(), allocator)
+                           });
+        // the P3 tables of the higher half are shared by all address spaces
+        if p2_freed && page.p4_index() < ENTRY_COUNT / 2 {
+            self.p4_mut().free_next_table_if_empty(page.p4_index(), allocator);
+        }
+    }
+
+    /// Creates the P3 table for the P4 entry of `page` if it doesn't exist yet.
+    ///
+    /// Address spaces share the P3 tables of the kernel, so these must exist before the first
+    /// address space is created.
+    pub fn create_p3_table<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        self.p4_mut().next_table_create(page.p4_index(), EntryFlags::empty(), allocator);
+    }
+
+    /// Creates the P3, P2 and P1 tables on the path to `page` that don't exist yet, so that
+    /// `page` can be mapped without allocating frames.
+    pub fn create_tables<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let flags = EntryFlags::empty();
+        let mut p3 = self.p4_mut().next_table_create(page.p4_index(), flags, allocator);
+        let mut p2 = p3.next_table_create(page.p3_index(), flags, allocator);
+        p2.next_table_create(page.p2_index(), flags, allocator);
+    }
+
+    /// Splits the 1GiB and 2MiB pages that contain `page` into smaller pages with the same
+    /// flags, so that `page` is mapped through a P1 table afterwards.
+    fn split_huge_pages<A>(&mut self, page: Page, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let huge_1g = self.p4()
+                          .next_table(page.p4_index())
+                          .map_or(false, |p3| p3[page.p3_index()].flags().contains(HUGE_PAGE));
+        if huge_1g {
+            let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+            // every P2 entry maps one 2MiB part of the 1GiB page
+            split_entry(p3, page.p3_index(), ENTRY_COUNT, HUGE_PAGE, allocator);
+        }
+
+        let huge_2m = self.p4()
+                          .next_table(page.p4_index())
+                          .and_then(|p3| p3.next_table(page.p3_index()))
+                          .map_or(false, |p2| p2[page.p2_index()].flags().contains(HUGE_PAGE));
+        if huge_2m {
+            let p2 = self.p4_mut()
+                         .next_table_mut(page.p4_index())
+                         .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                         .unwrap();
+            split_entry(p2, page.p2_index(), 1, EntryFlags::empty(), allocator);
+        }
+    }
+}
+
+/// Replaces the huge page entry `table[index]` with a new next level table whose entries map the
+/// same memory with the same flags. Each new entry maps `frames_per_entry` frames and gets
+/// `extra_flags` in addition to the flags of the huge page.
+fn split_entry<L, A>(table: &mut Table<L>,
+                     index: usize,
+                     frames_per_entry: usize,
+                     extra_flags: EntryFlags,
+                     allocator: &mut A)
+    where L: HierarchicalLevel,
+          A: FrameAllocator
+{
+    let start_frame = table[index].pointed_frame().unwrap();
+    let flags = table[index].flags() - HUGE_PAGE;
+
+    let table_frame = allocator.allocate_frame().expect("no frames available");
+    table[index].set(table_frame, PRESENT | WRITABLE | (flags & USER_ACCESSIBLE));
+
+    // the old huge page is still in the TLB until the flush below, so the code that runs here
+    // keeps working even if it lies inside the huge page
+    let next_table = table.next_table_mut(index).unwrap();
+    for i in 0..ENTRY_COUNT {
+        let frame = Frame { number: start_frame.number + i * frames_per_entry };
+        next_table[i].set(frame, flags | extra_flags);
+    }
+    unsafe { ::x86::tlb::flush_all() };
+}
+
+/// A run of consecutive mapped pages with identical flags. `end` is inclusive.
+#[derive(Debug, Clone, Copy)]
+pub struct MappingRun {
+    pub start: Page,
+    pub end: Page,
+    pub flags: EntryFlags,
+}
+
+pub struct MappingRuns<'a> {
+    mapper: &'a Mapper,
+    pages: PageIter,
+}
+
+impl<'a> Iterator for MappingRuns<'a> {
+    type Item = MappingRun;
+
+    fn next(&mut self) -> Option<MappingRun> {
+        // skip unmapped pages, all pages below a missing table at once
+        let mut first = None;
+        while self.pages.start <= self.pages.end {
+            let page = self.pages.start;
+            if let Some(flags) = self.mapper.page_flags(page) {
+                self.pages.start.number += 1;
+                first = Some((page, flags));
+                break;
+            }
+            self.pages.start.number += self.mapper.unmapped_pages(page);
+        }
+        let (start, flags) = match first {
+            Some(first) => first,
+            None => return None,
+        };
+
+        // extend the run as long as the flags stay the same
+        let mut end = start;
+        while self.pages.start <= self.pages.end &&
+              self.mapper.page_flags(self.pages.start) == Some(flags) {
+            end = self.pages.start;
+            self.pages.start.number += 1;
+        }
+
+        Some(MappingRun {
+            start: start,
+            end: end,
+            flags: flags,
+        })
     }
 }
@@ -1,7 +1,7 @@
 pub use self::entry::*;
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
-use self::temporary_page::TemporaryPage;
-pub use self::mapper::Mapper;
+use memory::{PAGE_SIZE, KERNEL_OFFSET, Frame, FrameAllocator, kernel_to_physical};
+pub use self::temporary_page::TemporaryPage;
+pub use self::mapper::{Mapper, MappingRun, MappingRuns};
 use core::ops::{Deref, DerefMut};
 use multiboot2::BootInformation;
 
@@ -12,6 +12,20 @@ mod mapper;
 
 const ENTRY_COUNT: usize = 512;
 
+/// The P4 entry that points to the P4 table itself. Entry 511 contains the kernel.
+const RECURSIVE_ENTRY: usize = 510;
+
+/// All physical memory is mapped at this virtual offset (P4 entry 256), so that every page table
+/// can be accessed directly instead of through the recursive mapping.
+#[cfg(feature = "physical_memory_offset")]
+pub const PHYSICAL_MEMORY_OFFSET: VirtualAddress = 0xffff_8000_0000_0000;
+
+/// Returns the virtual address of the given physical address in the physical memory mapping.
+#[cfg(feature = "physical_memory_offset")]
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    PHYSICAL_MEMORY_OFFSET + address
+}
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -28,7 +42,7 @@ impl Page {
         Page { number: address / PAGE_SIZE }
     }
 
-    fn start_address(&self) -> usize {
+    pub fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
@@ -72,6 +86,19 @@ impl Iterator for PageIter {
     }
 }
 
+/// Returns true if the CPU supports 1GiB pages (CPUID 0x80000001, EDX bit 26).
+pub fn huge_1g_supported() -> bool {
+    let (_eax, edx): (u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}"(_eax), "={edx}"(edx)
+             : "{eax}"(0x80000001u32)
+             : "ebx", "ecx"
+             : "volatile");
+    }
+    edx & (1 << 26) != 0
+}
+
 pub struct ActivePageTable {
     mapper: Mapper,
 }
@@ -91,15 +118,63 @@ impl DerefMut for ActivePageTable {
 }
 
 impl ActivePageTable {
-    unsafe fn new() -> ActivePageTable {
+    /// Creates a view of the currently loaded page table through the recursive mapping.
+    pub unsafe fn new() -> ActivePageTable {
         ActivePageTable { mapper: Mapper::new() }
     }
 
+    /// Copies the higher half P4 entries of the active table to `table`, so that both share the
+    /// kernel mappings.
+    pub fn copy_kernel_entries(&mut self,
+                               table: &mut InactivePageTable,
+                               temporary_page: &mut TemporaryPage) {
+        {
+            let p4_table = temporary_page.map_table_frame(table.p4_frame.clone(), self);
+            for index in (ENTRY_COUNT / 2)..ENTRY_COUNT {
+                if index == RECURSIVE_ENTRY {
+                    continue;
+                }
+                match self.p4()[index].pointed_frame() {
+                    Some(frame) => p4_table[index].set(frame, self.p4()[index].flags()),
+                    None => p4_table[index].set_unused(),
+                }
+            }
+        }
+        temporary_page.unmap(self);
+    }
+
+    /// Executes `f` with a mapper for the given inactive table.
+    #[cfg(not(feature = "physical_memory_offset"))]
     pub fn with<F>(&mut self,
                    table: &mut InactivePageTable,
-                   temporary_page: &mut temporary_page::TemporaryPage, // new
+                   temporary_page: &mut temporary_page::TemporaryPage,
                    f: F)
         where F: FnOnce(&mut Mapper)
+    {
+        self.with_recursive(table, temporary_page, f)
+    }
+
+    /// Executes `f` with a mapper for the given inactive table. The tables of `table` are
+    /// accessed through the physical memory mapping, so the temporary page is not needed.
+    #[cfg(feature = "physical_memory_offset")]
+    pub fn with<F>(&mut self,
+                   table: &mut InactivePageTable,
+                   _temporary_page: &mut temporary_page::TemporaryPage,
+                   f: F)
+        where F: FnOnce(&mut Mapper)
+    {
+        let p4 = phys_to_virt(table.p4_frame.start_address());
+        let mut mapper = unsafe { Mapper::with_p4(p4 as *mut _) };
+        f(&mut mapper);
+    }
+
+    /// Executes `f` with the recursive entry of the active P4 table pointing to the P4 table of
+    /// `table`, so that the recursive mapping accesses the inactive tables.
+    fn with_recursive<F>(&mut self,
+                         table: &mut InactivePageTable,
+                         temporary_page: &mut temporary_page::TemporaryPage,
+                         f: F)
+        where F: FnOnce(&mut Mapper)
     {
         use x86::{controlregs, tlb};
         let flush_tlb = || unsafe { tlb::flush_all() };
@@ -111,14 +186,14 @@ impl ActivePageTable {
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[RECURSIVE_ENTRY].set(table.p4_frame.clone(), PRESENT | WRITABLE);
             flush_tlb();
 
             // execute f in the new context
             f(self);
 
             // restore recursive mapping to original p4 table
-            p4_table[511].set(backup, PRESENT | WRITABLE);
+            p4_table[RECURSIVE_ENTRY].set(backup, PRESENT | WRITABLE);
             flush_tlb();
         }
 
@@ -150,69 +225,166 @@ impl InactivePageTable {
         {
             let table = temporary_page.map_table_frame(frame.clone(), active_table);
             table.zero();
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_ENTRY].set(frame.clone(), PRESENT | WRITABLE);
         }
         temporary_page.unmap(active_table);
 
         InactivePageTable { p4_frame: frame }
     }
+
+    /// Returns the physical address of the P4 table, i.e. the value of CR3 when it is loaded.
+    pub fn p4_address(&self) -> PhysicalAddress {
+        self.p4_frame.start_address()
+    }
+
+    /// Returns true if the table is loaded in CR3, e.g. because it belongs to the running
+    /// thread. It can be edited through the recursive mapping then.
+    pub fn is_loaded(&self) -> bool {
+        use x86::controlregs;
+        unsafe { controlregs::cr3() as usize == self.p4_address() }
+    }
 }
 
-pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
+/// Creates the kernel's page table and switches to it. Besides the kernel image, the VGA buffer
+/// and the multiboot information, the physical `frame_table` range of the frame allocator is
+/// mapped at its boot mapping address.
+pub fn remap_the_kernel<A>(allocator: &mut A,
+                           temporary_page: &mut TemporaryPage,
+                           boot_info: &BootInformation,
+                           frame_table: (PhysicalAddress, PhysicalAddress))
+                           -> ActivePageTable
     where A: FrameAllocator
 {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe }, allocator);
-
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
-        InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
+        InactivePageTable::new(frame, &mut active_table, temporary_page)
     };
 
-    active_table.with(&mut new_table, &mut temporary_page, |mapper| {
+    // the physical memory mapping does not exist yet, so the recursive mapping is used
+    let temporary_page_location = temporary_page.page();
+    active_table.with_recursive(&mut new_table, temporary_page, |mapper| {
         let elf_sections_tag = boot_info.elf_sections_tag()
                                         .expect("Memory map tag required");
 
-        // identity map the allocated kernel sections
+        // map the allocated kernel sections to their virtual addresses
         for section in elf_sections_tag.sections() {
             if !section.is_allocated() {
                 // section is not loaded to memory
                 continue;
             }
+            if section.start_address() < KERNEL_OFFSET {
+                // the boot code is not needed after the jump to the higher half
+                continue;
+            }
 
             assert!(section.addr as usize % PAGE_SIZE == 0,
                     "sections need to be page aligned");
-            kprintln!("mapping section at addr: {:#x}, size: {:#x}",
-                     section.addr,
-                     section.size);
+            klog!(Debug,
+                  "mapping section at addr: {:#x}, size: {:#x}",
+                  section.addr,
+                  section.size);
 
             let flags = EntryFlags::from_elf_section_flags(section);
 
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
-            for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+            let start_page = Page::containing_address(section.start_address());
+            let end_page = Page::containing_address(section.end_address() - 1);
+            for page in Page::range_inclusive(start_page, end_page) {
+                let frame = Frame::containing_address(kernel_to_physical(page.start_address()));
+                mapper.map_to(page, frame, flags, allocator);
             }
         }
 
-        // identity map the VGA text buffer
+        // map the VGA text buffer
         let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+        let vga_buffer_page = Page::containing_address(KERNEL_OFFSET + 0xb8000);
+        mapper.map_to(vga_buffer_page, vga_buffer_frame, WRITABLE, allocator);
+
+        // map the multiboot info structure, it was accessed through the higher half mapping of
+        // the boot page tables
+        let multiboot_start = Page::containing_address(boot_info.start_address());
+        let multiboot_end = Page::containing_address(boot_info.end_address() - 1);
+        for page in Page::range_inclusive(multiboot_start, multiboot_end) {
+            let frame = Frame::containing_address(kernel_to_physical(page.start_address()));
+            mapper.map_to(page, frame, PRESENT, allocator);
+        }
 
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
-        for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+        // the frame allocator keeps using its frame table through the same addresses
+        let (frame_table_start, frame_table_end) = frame_table;
+        let frames = Frame::range_inclusive(Frame::containing_address(frame_table_start),
+                                            Frame::containing_address(frame_table_end - 1));
+        for frame in frames {
+            let page = Page::containing_address(KERNEL_OFFSET + frame.start_address());
+            mapper.map_to(page, frame, WRITABLE | NO_EXECUTE, allocator);
         }
+
+        // the frames of the temporary page's allocator are used up by the tables of the boot
+        // table, so the new table gets its own tables for the temporary page
+        mapper.create_tables(temporary_page_location, allocator);
+
+        map_physical_memory(mapper, boot_info, allocator);
     });
 
     let old_table = active_table.switch(new_table);
-    kprintln!("NEW TABLE!!!");
-
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
-    kprintln!("guard page at {:#x}", old_p4_page.start_address());
+    klog!(Debug, "NEW TABLE!!!");
+
+    // the boot P4, P3 and P2 tables are part of the kernel's .bss section, directly below the
+    // boot stack, and turn into its guard pages
+    let old_p4_page = Page::containing_address(KERNEL_OFFSET +
+                                               old_table.p4_frame.start_address());
+    let old_p2_page = Page::containing_address(old_p4_page.start_address() + 2 * PAGE_SIZE);
+    for page in Page::range_inclusive(old_p4_page, old_p2_page) {
+        active_table.unmap(page, allocator);
+    }
+    klog!(Debug,
+          "guard pages at {:#x}..{:#x}",
+          old_p4_page.start_address(),
+          old_p2_page.start_address() + PAGE_SIZE);
 
     active_table
 }
+
+/// Maps all physical memory at `PHYSICAL_MEMORY_OFFSET`, using 1GiB pages if the CPU supports
+/// them and 2MiB pages otherwise.
+#[cfg(feature = "physical_memory_offset")]
+fn map_physical_memory<A>(mapper: &mut Mapper, boot_info: &BootInformation, allocator: &mut A)
+    where A: FrameAllocator
+{
+    let memory_end = boot_info.memory_map_tag()
+                              .expect("Memory map tag required")
+                              .memory_areas()
+                              .map(|area| (area.base_addr + area.length) as usize)
+                              .max()
+                              .unwrap();
+
+    let frames_per_page = if huge_1g_supported() {
+        ENTRY_COUNT * ENTRY_COUNT
+    } else {
+        ENTRY_COUNT
+    };
+    let page_size = frames_per_page * PAGE_SIZE;
+    klog!(Debug,
+          "mapping {:#x} bytes of physical memory at {:#x} with {:#x} byte pages",
+          memory_end,
+          PHYSICAL_MEMORY_OFFSET,
+          page_size);
+
+    let flags = WRITABLE | NO_EXECUTE;
+    let mut address = 0;
+    while address < memory_end {
+        let page = Page::containing_address(phys_to_virt(address));
+        let frame = Frame::containing_address(address);
+        if frames_per_page == ENTRY_COUNT {
+            mapper.map_to_huge_2m(page, frame, flags, allocator);
+        } else {
+            mapper.map_to_huge_1g(page, frame, flags, allocator);
+        }
+        address += page_size;
+    }
+}
+
+#[cfg(not(feature = "physical_memory_offset"))]
+fn map_physical_memory<A>(_mapper: &mut Mapper, _boot_info: &BootInformation, _allocator: &mut A)
+    where A: FrameAllocator
+{
+}
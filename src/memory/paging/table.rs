@@ -1,10 +1,27 @@
 use memory::paging::entry::*;
-use memory::paging::ENTRY_COUNT;
+use memory::paging::{ENTRY_COUNT, RECURSIVE_ENTRY};
+#[cfg(feature = "physical_memory_offset")]
+use memory::paging::phys_to_virt;
 use memory::FrameAllocator;
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+/// The address of the P4 table in the recursive mapping at P4 entry 510.
+pub const P4: *mut Table<Level4> = 0xffffff7f_bfdfe000 as *mut _;
+
+/// Copies bit 47 of the address to bits 48 to 63 to make it canonical.
+fn sign_extend(address: usize) -> usize {
+    if address & (1 << 47) != 0 {
+        address | 0xffff_0000_0000_0000
+    } else {
+        address
+    }
+}
+
+/// Returns true if the given table address lies in the recursive mapping.
+fn is_recursive_address(address: usize) -> bool {
+    (address >> 39) & 0o777 == RECURSIVE_ENTRY
+}
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -19,6 +36,10 @@ impl<L> Table<L>
             entry.set_unused();
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L>
@@ -28,12 +49,29 @@ impl<L> Table<L>
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            if is_recursive_address(table_address) {
+                let address = (table_address << 9) | (index << 12);
+                // the shift moves the recursive index out of bit 47, so sign extend again
+                Some(sign_extend(address & 0x0000_ffff_ffff_ffff))
+            } else {
+                Some(self.next_table_offset_address(index))
+            }
         } else {
             None
         }
     }
 
+    /// Returns the address of the next table in the physical memory mapping.
+    #[cfg(feature = "physical_memory_offset")]
+    fn next_table_offset_address(&self, index: usize) -> usize {
+        phys_to_virt(self[index].pointed_frame().unwrap().start_address())
+    }
+
+    #[cfg(not(feature = "physical_memory_offset"))]
+    fn next_table_offset_address(&self, _index: usize) -> usize {
+        unreachable!("page tables can only be accessed through the recursive mapping");
+    }
+
     pub fn next_table(&self, index: usize) -> Option<&Table<L::NextLevel>> {
         self.next_table_address(index)
             .map(|address| unsafe { &*(address as *const _) })
@@ -44,21 +82,45 @@ impl<L> Table<L>
             .map(|address| unsafe { &mut *(address as *mut _) })
     }
 
+    /// Returns the next table at `index` and creates it if it doesn't exist. The entry gets
+    /// `flags` in addition to `PRESENT | WRITABLE`, e.g. `USER_ACCESSIBLE` for tables that
+    /// contain user pages.
     pub fn next_table_create<A>(&mut self,
                                 index: usize,
+                                flags: EntryFlags,
                                 allocator: &mut A)
                                 -> &mut Table<L::NextLevel>
         where A: FrameAllocator
     {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                    "mapping code does not support huge pages");
+                    "cannot create a table below a huge page");
             let frame = allocator.allocate_frame().expect("no frames available");
-            self.entries[index].set(frame, PRESENT | WRITABLE);
+            self.entries[index].set(frame, PRESENT | WRITABLE | flags);
             self.next_table_mut(index).unwrap().zero();
+        } else if !self.entries[index].flags().contains(flags) {
+            let frame = self.entries[index].pointed_frame().unwrap();
+            let entry_flags = self.entries[index].flags() | flags;
+            self.entries[index].set(frame, entry_flags);
         }
         self.next_table_mut(index).unwrap()
     }
+
+    /// Frees the next table at `index` if all of its entries are unused.
+    /// Returns true if the table was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        let table_address = match self.next_table(index) {
+            Some(table) if table.is_empty() => table as *const _ as usize,
+            _ => return false,
+        };
+        let frame = self[index].pointed_frame().unwrap();
+        self[index].set_unused();
+        unsafe { ::x86::tlb::flush(table_address) };
+        allocator.deallocate_frame(frame);
+        true
+    }
 }
 
 impl<L> Index<usize> for Table<L>
@@ -17,6 +17,10 @@ impl TemporaryPage {
         }
     }
 
+    pub fn page(&self) -> Page {
+        self.page
+    }
+
     /// Maps the temporary page to the given frame in the active table.
     /// Returns the start address of the temporary page.
     pub fn map(&mut self, frame: Frame, active_table: &mut ActivePageTable) -> VirtualAddress {
@@ -38,8 +42,11 @@ impl TemporaryPage {
     }
 
     /// Unmaps the temporary page in the active table.
+    ///
+    /// The page tables are kept, because the tiny allocator can only take back the frames it
+    /// handed out itself.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        active_table.unmap_keep_tables(self.page, &mut self.allocator);
     }
 }
 
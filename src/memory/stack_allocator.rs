@@ -0,0 +1,158 @@
+use memory::{PAGE_SIZE, KERNEL_SPACE, FRAME_ALLOCATOR};
+use memory::paging::{Page, PageIter, VirtualAddress, WRITABLE, NO_EXECUTE};
+use spin::Mutex;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The largest stack that `alloc_stack` hands out, in pages.
+pub const MAX_STACK_PAGES: usize = 16;
+
+/// Every stack gets a slot of this many pages and ends at the end of its slot. The lowest page of
+/// a slot is never mapped, so a stack overflow hits this guard page instead of the stack below.
+const SLOT_PAGES: usize = MAX_STACK_PAGES + 1;
+
+/// The maximum number of stacks that can exist at the same time.
+const STACK_SLOTS: usize = 512;
+
+/// The size of the virtual region that is reserved for stacks.
+pub const STACK_AREA_SIZE: usize = STACK_SLOTS * SLOT_PAGES * PAGE_SIZE;
+
+/// The size of the boot stack in `boot.asm`.
+const BOOT_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+/// The start of the stack region. It is set by `memory::init`.
+static AREA_START: AtomicUsize = AtomicUsize::new(0);
+
+/// It is never held while `KERNEL_SPACE` or `FRAME_ALLOCATOR` are taken.
+static STACK_ALLOCATOR: Mutex<StackAllocator> = Mutex::new(StackAllocator::new());
+
+extern "C" {
+    /// The end of the boot stack, defined in `boot.asm`.
+    #[link_name = "stack_top"]
+    static BOOT_STACK_TOP: u8;
+}
+
+/// A kernel stack. Its pages are unmapped and its frames freed when it is dropped.
+#[derive(Debug)]
+pub struct Stack {
+    top: VirtualAddress,
+    bottom: VirtualAddress,
+    slot: usize,
+}
+
+impl Stack {
+    /// The end of the stack, i.e. the initial stack pointer. The stack grows downwards.
+    pub fn top(&self) -> VirtualAddress {
+        self.top
+    }
+
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    fn pages(&self) -> PageIter {
+        Page::range_inclusive(Page::containing_address(self.bottom),
+                              Page::containing_address(self.top - 1))
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        {
+            let mut kernel_space = KERNEL_SPACE.lock();
+            let kernel_space = kernel_space.as_mut().expect("memory is not initialized");
+            let mut frame_allocator = FRAME_ALLOCATOR.lock();
+            let pages = self.pages();
+            kernel_space.with_mapper(|mapper| mapper.unmap_range(pages, &mut *frame_allocator));
+        }
+        STACK_ALLOCATOR.lock().free(self.slot);
+    }
+}
+
+struct StackAllocator {
+    /// One bit per slot, set if the slot is in use.
+    used: [u64; STACK_SLOTS / 64],
+}
+
+impl StackAllocator {
+    const fn new() -> StackAllocator {
+        StackAllocator {
+            used: [0; STACK_SLOTS / 64],
+        }
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        assert!(AREA_START.load(Ordering::SeqCst) != 0, "stack allocator is not initialized");
+        for slot in 0..STACK_SLOTS {
+            let (word, bit) = (slot / 64, 1 << (slot % 64));
+            if self.used[word] & bit == 0 {
+                self.used[word] |= bit;
+                return Some(slot);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, slot: usize) {
+        self.used[slot / 64] &= !(1 << (slot % 64));
+    }
+
+}
+
+fn slot_end(slot: usize) -> VirtualAddress {
+    AREA_START.load(Ordering::SeqCst) + (slot + 1) * SLOT_PAGES * PAGE_SIZE
+}
+
+/// Sets the virtual region `[start, start + STACK_AREA_SIZE)` that the stacks are placed in.
+pub fn init(start: VirtualAddress) {
+    AREA_START.store(start, Ordering::SeqCst);
+}
+
+/// Returns the end of the kernel stack that contains `address`, or `None` if `address` is on no
+/// kernel stack. It takes no locks, so that the heap can call it in any context.
+pub fn stack_top(address: VirtualAddress) -> Option<VirtualAddress> {
+    let boot_stack_top = unsafe { &BOOT_STACK_TOP as *const u8 as VirtualAddress };
+    if address < boot_stack_top && address >= boot_stack_top - BOOT_STACK_SIZE {
+        return Some(boot_stack_top);
+    }
+    let area_start = AREA_START.load(Ordering::SeqCst);
+    if area_start == 0 || address < area_start || address >= area_start + STACK_AREA_SIZE {
+        return None;
+    }
+    Some(slot_end((address - area_start) / (SLOT_PAGES * PAGE_SIZE)))
+}
+
+/// Allocates a kernel stack of `pages` pages with a guard page below it. Returns `None` if there
+/// are too many stacks or not enough free frames.
+pub fn alloc_stack(pages: usize) -> Option<Stack> {
+    assert!(pages > 0 && pages <= MAX_STACK_PAGES,
+            "stacks must have between 1 and {} pages",
+            MAX_STACK_PAGES);
+
+    let slot = match STACK_ALLOCATOR.lock().allocate() {
+        Some(slot) => slot,
+        None => return None,
+    };
+    // the pages below the stack, at least the guard page, stay unmapped
+    let top = slot_end(slot);
+    let bottom = top - pages * PAGE_SIZE;
+
+    let mut kernel_space = KERNEL_SPACE.lock();
+    let kernel_space = kernel_space.as_mut().expect("memory is not initialized");
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    // the page tables might need frames, too
+    if frame_allocator.free_frames() < pages + 2 {
+        STACK_ALLOCATOR.lock().free(slot);
+        return None;
+    }
+
+    let stack = Stack {
+        top: top,
+        bottom: bottom,
+        slot: slot,
+    };
+    let stack_pages = stack.pages();
+    kernel_space.with_mapper(|mapper| {
+        mapper.map_range(stack_pages, WRITABLE | NO_EXECUTE, &mut *frame_allocator)
+    });
+    Some(stack)
+}
@@ -0,0 +1,81 @@
+//! Access to the multiboot2 tags that the multiboot2 crate doesn't parse, like the boot modules
+//! and the command line.
+//!
+//! The multiboot information structure stays mapped, so data that points into it is `'static`.
+
+use core::{slice, str};
+use multiboot2::BootInformation;
+
+pub const TAG_END: u32 = 0;
+pub const TAG_COMMAND_LINE: u32 = 1;
+pub const TAG_MODULE: u32 = 3;
+
+/// A tag of the multiboot information structure.
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    pub kind: u32,
+    /// The virtual address of the tag, starting with its type.
+    pub address: usize,
+    /// The size of the tag, including the type and size fields.
+    pub size: usize,
+}
+
+impl Tag {
+    /// Reads the `u32` at `offset` in the tag.
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        assert!(offset + 4 <= self.size, "read outside of the tag");
+        unsafe { *((self.address + offset) as *const u32) }
+    }
+
+    /// Reads the null terminated string at `offset`, which ends at the end of the tag at the
+    /// latest. Invalid UTF-8 is returned as an empty string.
+    pub fn string(&self, offset: usize) -> &'static str {
+        if offset >= self.size {
+            return "";
+        }
+        let bytes = unsafe {
+            slice::from_raw_parts((self.address + offset) as *const u8, self.size - offset)
+        };
+        let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..len]).unwrap_or("")
+    }
+}
+
+pub struct TagIter {
+    current: usize,
+    end: usize,
+}
+
+/// Returns all tags of the multiboot information structure.
+pub fn tags(boot_info: &BootInformation) -> TagIter {
+    TagIter {
+        // the tags follow the total size and a reserved field
+        current: boot_info.start_address() + 8,
+        end: boot_info.end_address(),
+    }
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.current + 8 > self.end {
+            return None;
+        }
+        let address = self.current;
+        let (kind, size) = unsafe {
+            (*(address as *const u32), *((address + 4) as *const u32) as usize)
+        };
+        if kind == TAG_END || size < 8 {
+            self.current = self.end;
+            return None;
+        }
+        // tags are 8-byte aligned
+        self.current = address + ((size + 7) & !7);
+        Some(Tag {
+            kind: kind,
+            address: address,
+            size: size,
+        })
+    }
+}
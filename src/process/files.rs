@@ -0,0 +1,101 @@
+//! File descriptor tables.
+//!
+//! A file descriptor refers to an open file, which is shared by all descriptors that were
+//! copied from it, e.g. by `fork`.
+
+use alloc::arc::Arc;
+use collections::Vec;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// The maximum number of open file descriptors of a process.
+pub const MAX_FILES: usize = 64;
+
+/// An open file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum File {
+    /// Reads from the keyboard and writes to the VGA text buffer.
+    Console,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileDescriptor {
+    pub file: Arc<File>,
+    /// The descriptor is closed by `execve`.
+    pub close_on_exec: bool,
+}
+
+/// The open file descriptors of a process, indexed by their number.
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    descriptors: Vec<Option<FileDescriptor>>,
+}
+
+impl FileTable {
+    pub fn new() -> FileTable {
+        FileTable { descriptors: Vec::new() }
+    }
+
+    /// Creates a table with the console open as standard input, output and error.
+    pub fn with_console() -> FileTable {
+        let console = Arc::new(File::Console);
+        let mut table = FileTable::new();
+        for _ in &[STDIN, STDOUT, STDERR] {
+            table.insert(FileDescriptor {
+                file: console.clone(),
+                close_on_exec: false,
+            });
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Option<&FileDescriptor> {
+        self.descriptors.get(fd).and_then(|descriptor| descriptor.as_ref())
+    }
+
+    pub fn get_mut(&mut self, fd: usize) -> Option<&mut FileDescriptor> {
+        self.descriptors.get_mut(fd).and_then(|descriptor| descriptor.as_mut())
+    }
+
+    /// Opens `descriptor` with the lowest free number, which is returned. Returns `None` if
+    /// `MAX_FILES` descriptors are open.
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> Option<usize> {
+        match self.descriptors.iter().position(|descriptor| descriptor.is_none()) {
+            Some(fd) => {
+                self.descriptors[fd] = Some(descriptor);
+                Some(fd)
+            }
+            None if self.descriptors.len() < MAX_FILES => {
+                self.descriptors.push(Some(descriptor));
+                Some(self.descriptors.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    /// Closes `fd` and returns its descriptor, or `None` if it isn't open.
+    pub fn close(&mut self, fd: usize) -> Option<FileDescriptor> {
+        let descriptor = self.descriptors.get_mut(fd).and_then(|descriptor| descriptor.take());
+        self.shrink();
+        descriptor
+    }
+
+    /// Closes the descriptors that are marked close-on-exec.
+    pub fn close_on_exec(&mut self) {
+        for descriptor in self.descriptors.iter_mut() {
+            if descriptor.as_ref().map_or(false, |descriptor| descriptor.close_on_exec) {
+                *descriptor = None;
+            }
+        }
+        self.shrink();
+    }
+
+    /// Removes the closed descriptors at the end.
+    fn shrink(&mut self) {
+        while let Some(&None) = self.descriptors.last() {
+            self.descriptors.pop();
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Processes.
+//!
+//! A process owns an address space, a file descriptor table and credentials, and runs one or
+//! more user threads in its address space. Every process except init has a parent. When the last
+//! thread of a process exits, its memory is freed and the process becomes a zombie that keeps
+//! its exit status until the parent reaps it with `wait`, which also frees the threads. The
+//! children of an exiting process are adopted by init, which has to reap them instead.
+//!
+//! `fork` copies a process, sharing its memory copy-on-write, and `exec` replaces the program
+//! that a process runs.
+//!
+//! The process table is protected by a sleeping mutex, so interrupt handlers must not use it.
+//! Exceptions in user mode are the exception: they run on the kernel stack of the faulting thread
+//! without holding any locks, so they can sleep.
+
+use alloc::arc::Arc;
+use collections::{BTreeMap, Vec};
+use core::mem;
+use spin::Mutex;
+use boot_modules;
+use elf::{self, Image};
+use memory::{self, AddressSpace};
+use sync::{self, Condvar};
+use sync::lock_order;
+use syscall::SyscallFrame;
+use task::{self, ThreadId, UserStart};
+
+pub use self::files::{File, FileDescriptor, FileTable, MAX_FILES, STDIN, STDOUT, STDERR};
+
+mod files;
+
+pub type Pid = usize;
+
+/// The first process. It adopts the children of exiting processes and must not exit.
+pub const INIT_PID: Pid = 1;
+
+/// PIDs are allocated below this limit.
+const PID_LIMIT: Pid = 32_768;
+
+/// The number of signals, including the unused signal 0.
+pub const SIGNAL_COUNT: usize = 32;
+
+pub const SIGKILL: u8 = 9;
+/// The signal that an exception in user mode kills the process with.
+pub const SIGSEGV: u8 = 11;
+pub const SIGSTOP: u8 = 19;
+
+/// The signal handler that takes the default action.
+pub const SIG_DFL: usize = 0;
+/// The signal handler that ignores the signal.
+pub const SIG_IGN: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The process has no child that matches.
+    NoChildren,
+    /// All PIDs are in use.
+    NoFreePid,
+    /// The executable couldn't be loaded.
+    Exec(elf::Error),
+}
+
+impl From<elf::Error> for Error {
+    fn from(error: elf::Error) -> Error {
+        Error::Exec(error)
+    }
+}
+
+/// The user and group ids of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub euid: u32,
+    pub egid: u32,
+}
+
+/// The credentials of init. There are no other users yet.
+pub const ROOT: Credentials = Credentials {
+    uid: 0,
+    gid: 0,
+    euid: 0,
+    egid: 0,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The last thread called `exit` with this status.
+    Exited(u8),
+    /// The process was killed by this signal.
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// Encodes the status like the `wstatus` of `waitpid` on Linux.
+    pub fn to_wait_status(self) -> u32 {
+        match self {
+            ExitStatus::Exited(code) => (code as u32) << 8,
+            ExitStatus::Killed(signal) => signal as u32,
+        }
+    }
+}
+
+pub struct Process {
+    /// 0 for init.
+    pub parent: Pid,
+    /// Shared with the threads of the process.
+    pub space: Arc<Mutex<AddressSpace>>,
+    pub files: FileTable,
+    pub credentials: Credentials,
+    /// `SIG_DFL`, `SIG_IGN` or the address of a function for every signal. Signals can't be sent
+    /// yet, so the handlers are only recorded.
+    pub signal_handlers: [usize; SIGNAL_COUNT],
+    /// All threads that were started in the process. The exited ones are freed when the process
+    /// is reaped.
+    threads: Vec<ThreadId>,
+    running_threads: usize,
+    children: Vec<Pid>,
+    /// `Some` if the process is a zombie.
+    exit_status: Option<ExitStatus>,
+}
+
+impl Process {
+    /// Resets the signals that have a handler function to their default action. Ignored signals
+    /// stay ignored.
+    fn reset_signal_handlers(&mut self) {
+        for handler in self.signal_handlers.iter_mut().filter(|handler| **handler != SIG_IGN) {
+            *handler = SIG_DFL;
+        }
+    }
+}
+
+struct Processes {
+    processes: BTreeMap<Pid, Process>,
+    pids: PidAllocator,
+}
+
+static PROCESSES: sync::Mutex<Option<Processes>> =
+    sync::Mutex::with_level(None, "PROCESSES", lock_order::PROCESSES);
+
+/// Notified whenever a process becomes a zombie.
+static CHILD_EXITED: Condvar = Condvar::new();
+
+impl Processes {
+    fn process_mut(&mut self, pid: Pid) -> &mut Process {
+        self.processes.get_mut(&pid).expect("no such process")
+    }
+
+    fn allocate_pid(&mut self) -> Result<Pid, Error> {
+        let processes = &self.processes;
+        self.pids.allocate(|pid| processes.contains_key(&pid)).ok_or(Error::NoFreePid)
+    }
+
+    /// Adds `process` to the table and to the children of its parent, and starts its first
+    /// thread. The thread can't use the process table before the lock is released.
+    fn start(&mut self, pid: Pid, process: Process, start: UserStart) {
+        let parent = process.parent;
+        let space = process.space.clone();
+        self.processes.insert(pid, process);
+        if let Some(parent) = self.processes.get_mut(&parent) {
+            parent.children.push(pid);
+        }
+        let thread = task::spawn_user(pid, space, start);
+        self.process_mut(pid).threads.push(thread);
+    }
+
+    /// Turns the process `pid`, whose threads have exited, into a zombie. Its children are
+    /// adopted by init.
+    fn make_zombie(&mut self, pid: Pid, status: ExitStatus) {
+        assert!(pid != INIT_PID, "init exited with {:?}", status);
+        let children = {
+            let process = self.process_mut(pid);
+            process.exit_status = Some(status);
+            // the user memory is unmapped by `exit`, only the empty address space is kept until
+            // the threads are freed
+            process.files = FileTable::new();
+            mem::replace(&mut process.children, Vec::new())
+        };
+        for &child in &children {
+            self.process_mut(child).parent = INIT_PID;
+        }
+        self.process_mut(INIT_PID).children.extend(children);
+    }
+
+    /// Removes a zombie child of `parent`, or the child `pid` if it is set, from the table.
+    /// Returns `Ok(None)` if the children that match are still running.
+    fn reap(&mut self, parent: Pid, pid: Option<Pid>) -> Result<Option<(Pid, Process)>, Error> {
+        let zombie = {
+            let processes = &self.processes;
+            let mut children = processes[&parent]
+                .children
+                .iter()
+                .cloned()
+                .filter(|&child| pid.map_or(true, |pid| child == pid))
+                .peekable();
+            if children.peek().is_none() {
+                return Err(Error::NoChildren);
+            }
+            children.find(|child| processes[child].exit_status.is_some())
+        };
+        match zombie {
+            Some(child) => {
+                self.process_mut(parent).children.retain(|&other| other != child);
+                Ok(Some((child, self.processes.remove(&child).unwrap())))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Starts the boot module `name` as init. Without it, no user programs run.
+pub fn init(name: &'static str) {
+    assert_has_not_been_called!("process::init must be called only once");
+
+    *PROCESSES.lock() = Some(Processes {
+        processes: BTreeMap::new(),
+        pids: PidAllocator::new(),
+    });
+
+    let module = match boot_modules::find(name) {
+        Some(module) => module,
+        None => {
+            klog!(Error, "no init module {:?}", name);
+            return;
+        }
+    };
+    match spawn(module.data, &[name.as_bytes()], &[]) {
+        Ok(pid) => assert_eq!(pid, INIT_PID),
+        Err(error) => klog!(Error, "init {:?} could not be started: {:?}", name, error),
+    }
+}
+
+/// Loads the executable in `data` into a new process and starts it with the arguments `argv`
+/// and the environment `envp`. The process is a child of the running process, or of init if a
+/// kernel thread spawns it, and has the console open as standard input, output and error.
+pub fn spawn(data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Pid, Error> {
+    let mut space = memory::new_user_space();
+    let image = try!(elf::load(&mut space, data, argv, envp));
+    let space = Arc::new(Mutex::new(space));
+
+    let mut processes = PROCESSES.lock();
+    let processes = processes.as_mut().expect("process::init was not called");
+    let pid = try!(processes.allocate_pid());
+    let parent = match task::current_process() {
+        _ if pid == INIT_PID => 0,
+        Some(current) => current,
+        None => INIT_PID,
+    };
+    let credentials = processes.processes.get(&parent).map_or(ROOT, |parent| parent.credentials);
+    let process = Process {
+        parent: parent,
+        space: space,
+        files: FileTable::with_console(),
+        credentials: credentials,
+        signal_handlers: [SIG_DFL; SIGNAL_COUNT],
+        threads: Vec::new(),
+        running_threads: 1,
+        children: Vec::new(),
+        exit_status: None,
+    };
+    let start = UserStart::Entry {
+        entry: image.entry,
+        stack_top: image.stack_pointer,
+    };
+    processes.start(pid, process, start);
+    Ok(pid)
+}
+
+/// Creates a child of the running process. It gets a copy of the address space, whose writable
+/// pages are shared copy-on-write, and of the file descriptor table, which refer to the same open
+/// files. Only the running thread is copied: the child's thread returns to user mode with the
+/// registers in `frame`. Returns the PID of the child.
+pub fn fork(frame: SyscallFrame) -> Result<Pid, Error> {
+    let parent = current();
+    let mut processes = PROCESSES.lock();
+    let processes = processes.as_mut().unwrap();
+    let pid = try!(processes.allocate_pid());
+    let process = {
+        let parent_process = processes.process_mut(parent);
+        let space = parent_process.space.lock().clone_cow();
+        Process {
+            parent: parent,
+            space: Arc::new(Mutex::new(space)),
+            files: parent_process.files.clone(),
+            credentials: parent_process.credentials,
+            signal_handlers: parent_process.signal_handlers,
+            threads: Vec::new(),
+            running_threads: 1,
+            children: Vec::new(),
+            exit_status: None,
+        }
+    };
+    processes.start(pid, process, UserStart::Resume(frame));
+    Ok(pid)
+}
+
+/// Replaces the program of the running process with the executable in `data` and its arguments
+/// `argv` and environment `envp`. The file descriptors that are marked close-on-exec are closed
+/// and the signal handlers are reset. Returns where the caller continues in user mode. If the
+/// executable can't be loaded, the process is unchanged.
+pub fn exec(data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Image, Error> {
+    let pid = current();
+    let mut space = memory::new_user_space();
+    let image = try!(elf::load(&mut space, data, argv, envp));
+    let space = Arc::new(Mutex::new(space));
+
+    let mut processes = PROCESSES.lock();
+    let old_space = {
+        let process = processes.as_mut().unwrap().process_mut(pid);
+        assert!(process.running_threads == 1, "exec in a process with several threads");
+        process.files.close_on_exec();
+        process.reset_signal_handlers();
+        mem::replace(&mut process.space, space.clone())
+    };
+    drop(processes);
+    // the old address space is freed when both references are dropped, after it was unloaded
+    let old_thread_space = task::replace_space(space);
+    drop(old_thread_space);
+    drop(old_space);
+    Ok(image)
+}
+
+/// Returns the process of the running thread. Panics for kernel threads.
+pub fn current() -> Pid {
+    task::current_process().expect("kernel threads don't belong to a process")
+}
+
+/// Calls `f` with the process of the running thread.
+pub fn with_current<F, T>(f: F) -> T
+    where F: FnOnce(&mut Process) -> T
+{
+    let pid = current();
+    let mut processes = PROCESSES.lock();
+    let process = processes.as_mut().unwrap().process_mut(pid);
+    f(process)
+}
+
+/// Ends the running thread. If it is the last thread of its process, the process exits with
+/// `status` and its user memory is freed.
+pub fn exit(status: ExitStatus) -> ! {
+    if let Some(pid) = task::current_process() {
+        let exited_space = {
+            let mut processes = PROCESSES.lock();
+            let processes = processes.as_mut().unwrap();
+            let last = {
+                let process = processes.process_mut(pid);
+                process.running_threads -= 1;
+                process.running_threads == 0
+            };
+            if last {
+                processes.make_zombie(pid, status);
+                Some(processes.process_mut(pid).space.clone())
+            } else {
+                None
+            }
+        };
+        if let Some(space) = exited_space {
+            // no other thread runs in the address space, and this one doesn't return to it
+            task::leave_space();
+            space.lock().unmap_lower_half();
+        }
+        CHILD_EXITED.notify_all();
+    }
+    task::exit();
+}
+
+/// Waits until the child `pid` of the running process, or any child if `pid` is `None`, has
+/// exited and reaps it. Returns its PID and exit status, or `None` if `block` is false and no
+/// matching child has exited yet.
+pub fn wait(pid: Option<Pid>, block: bool) -> Result<Option<(Pid, ExitStatus)>, Error> {
+    let current = try!(task::current_process().ok_or(Error::NoChildren));
+    let mut processes = PROCESSES.lock();
+    loop {
+        let reaped = try!(processes.as_mut().unwrap().reap(current, pid));
+        if let Some((child, zombie)) = reaped {
+            drop(processes);
+            // the last thread might not have switched away yet, `join` waits for it
+            for &thread in &zombie.threads {
+                task::join(thread);
+            }
+            return Ok(Some((child, zombie.exit_status.unwrap())));
+        }
+        if !block {
+            return Ok(None);
+        }
+        processes = CHILD_EXITED.wait(processes);
+    }
+}
+
+/// Hands out PIDs in increasing order. After `PID_LIMIT`, it starts again after init, so that
+/// PIDs are reused as late as possible.
+struct PidAllocator {
+    next: Pid,
+}
+
+impl PidAllocator {
+    fn new() -> PidAllocator {
+        PidAllocator { next: INIT_PID }
+    }
+
+    /// Returns the next PID for which `in_use` returns false, or `None` if all are in use.
+    fn allocate<F>(&mut self, in_use: F) -> Option<Pid>
+        where F: Fn(Pid) -> bool
+    {
+        for _ in INIT_PID..PID_LIMIT {
+            let pid = self.next;
+            self.next = if pid + 1 == PID_LIMIT { INIT_PID + 1 } else { pid + 1 };
+            if !in_use(pid) {
+                return Some(pid);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PidAllocator, INIT_PID, PID_LIMIT};
+
+    #[test]
+    fn allocates_pids_in_order() {
+        let mut pids = PidAllocator::new();
+        assert_eq!(pids.allocate(|_| false), Some(INIT_PID));
+        assert_eq!(pids.allocate(|_| false), Some(INIT_PID + 1));
+        assert_eq!(pids.allocate(|pid| pid == INIT_PID + 2), Some(INIT_PID + 3));
+    }
+
+    #[test]
+    fn reuses_pids_after_the_limit() {
+        let mut pids = PidAllocator::new();
+        pids.next = PID_LIMIT - 1;
+        assert_eq!(pids.allocate(|_| false), Some(PID_LIMIT - 1));
+        // init keeps its PID
+        assert_eq!(pids.allocate(|pid| pid == 2), Some(3));
+        assert_eq!(pids.allocate(|_| true), None);
+    }
+}
@@ -0,0 +1,35 @@
+use interrupts;
+use super::{MutexGuard, WaitQueue};
+
+/// A condition variable for the sleeping `Mutex`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { waiters: WaitQueue::new() }
+    }
+
+    /// Releases the mutex, sleeps until the condition variable is notified, and acquires the
+    /// mutex again. Wakeups can be spurious, so the caller has to check its condition in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // with interrupts disabled, no notification can come between the unlock and the wait
+        interrupts::without_interrupts(|| {
+            drop(guard);
+            self.waiters.wait();
+        });
+        mutex.lock()
+    }
+
+    /// Wakes one waiting thread.
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    /// Wakes all waiting threads.
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
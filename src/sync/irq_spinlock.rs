@@ -0,0 +1,103 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use x86::irq;
+use interrupts;
+use super::lock_order;
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Locks that are also taken by interrupt handlers must be of this kind: if the interrupt
+/// arrived while a `spin::Mutex` is held, the handler would spin forever. The holder must not
+/// sleep.
+pub struct IrqSpinlock<T> {
+    inner: Mutex<T>,
+    name: &'static str,
+    level: u8,
+}
+
+impl<T> IrqSpinlock<T> {
+    /// Creates a lock that is not checked by the lock order debugging.
+    pub const fn new(value: T) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            inner: Mutex::new(value),
+            name: "unnamed spinlock",
+            level: lock_order::UNORDERED,
+        }
+    }
+
+    /// Creates a lock with a place in the lock order, see `lock_order`.
+    pub const fn with_level(value: T, name: &'static str, level: u8) -> IrqSpinlock<T> {
+        IrqSpinlock {
+            inner: Mutex::new(value),
+            name: name,
+            level: level,
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is acquired. Interrupts are restored when
+    /// the guard is dropped.
+    pub fn lock(&self) -> IrqSpinlockGuard<T> {
+        let interrupts_enabled = interrupts::enabled();
+        unsafe { irq::disable() };
+        lock_order::acquire(self.name, self.level, true);
+        IrqSpinlockGuard {
+            lock: self,
+            guard: Some(self.inner.lock()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+
+    /// Acquires the lock if it is free.
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<T>> {
+        let interrupts_enabled = interrupts::enabled();
+        unsafe { irq::disable() };
+        match self.inner.try_lock() {
+            Some(guard) => {
+                lock_order::acquire(self.name, self.level, true);
+                Some(IrqSpinlockGuard {
+                    lock: self,
+                    guard: Some(guard),
+                    interrupts_enabled: interrupts_enabled,
+                })
+            }
+            None => {
+                if interrupts_enabled {
+                    unsafe { irq::enable() };
+                }
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqSpinlockGuard<'a, T: 'a> {
+    lock: &'a IrqSpinlock<T>,
+    /// Always `Some` until the guard is dropped.
+    guard: Option<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<'a, T> Deref for IrqSpinlockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinlockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for IrqSpinlockGuard<'a, T> {
+    fn drop(&mut self) {
+        // the lock must be released before interrupts are enabled again
+        self.guard.take();
+        lock_order::release(self.lock.name, self.lock.level);
+        if self.interrupts_enabled {
+            unsafe { irq::enable() };
+        }
+    }
+}
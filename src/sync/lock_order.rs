@@ -0,0 +1,139 @@
+//! Lock-order checking for debug builds.
+//!
+//! Every lock can be given a level. A thread may only acquire a lock whose level is higher than
+//! the levels of all locks it already holds, so two threads can never wait for each other. Locks
+//! with level `UNORDERED` are not checked, but they are still recorded, so that sleeping with an
+//! `IrqSpinlock` held is detected.
+//!
+//! The held locks of the running thread are kept in a global list, which the task module swaps
+//! on every thread switch. In release builds, nothing is recorded.
+
+use core::fmt;
+use spin::Mutex;
+use interrupts;
+
+/// Locks with this level are not ordered.
+pub const UNORDERED: u8 = 0;
+
+// The levels of the kernel's locks, the locks that are acquired first come first.
+pub const PROCESSES: u8 = 5;
+pub const KEYBOARD: u8 = 10;
+pub const CONSOLE: u8 = 20;
+
+/// The maximum number of locks a thread can hold at the same time.
+const MAX_HELD: usize = 16;
+
+#[derive(Clone, Copy)]
+struct HeldLock {
+    name: &'static str,
+    level: u8,
+    spinlock: bool,
+}
+
+/// The locks held by a thread.
+#[derive(Clone, Copy)]
+pub struct HeldLocks {
+    locks: [HeldLock; MAX_HELD],
+    count: usize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> HeldLocks {
+        HeldLocks {
+            locks: [HeldLock {
+                name: "",
+                level: UNORDERED,
+                spinlock: false,
+            }; MAX_HELD],
+            count: 0,
+        }
+    }
+
+    fn held(&self) -> &[HeldLock] {
+        &self.locks[..self.count]
+    }
+}
+
+impl fmt::Debug for HeldLocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.held().iter().map(|lock| (lock.name, lock.level))).finish()
+    }
+}
+
+/// The locks held by the running thread.
+static HELD: Mutex<HeldLocks> = Mutex::new(HeldLocks::new());
+
+/// Records that the running thread acquires the lock `name`. Panics if this violates the lock
+/// order. Called before the lock is taken, so that the violation is reported instead of a
+/// deadlock.
+pub fn acquire(name: &'static str, level: u8, spinlock: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut held = HELD.lock();
+        if level != UNORDERED {
+            for lock in held.held() {
+                assert!(lock.level == UNORDERED || lock.level < level,
+                        "lock order violation: acquiring {} (level {}) while holding {} \
+                         (level {})",
+                        name,
+                        level,
+                        lock.name,
+                        lock.level);
+            }
+        }
+        let count = held.count;
+        assert!(count < MAX_HELD, "too many locks held, acquiring {}", name);
+        held.locks[count] = HeldLock {
+            name: name,
+            level: level,
+            spinlock: spinlock,
+        };
+        held.count += 1;
+    });
+}
+
+/// Records that the running thread released the lock `name`. Locks don't have to be released in
+/// the reverse order.
+pub fn release(name: &'static str, level: u8) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let mut held = HELD.lock();
+        let count = held.count;
+        let index = held.held()
+            .iter()
+            .rposition(|lock| lock.name == name && lock.level == level)
+            .expect("released a lock that is not held");
+        for i in index..(count - 1) {
+            held.locks[i] = held.locks[i + 1];
+        }
+        held.count -= 1;
+    });
+}
+
+/// Panics if the running thread holds an `IrqSpinlock`, because it must not sleep then.
+pub fn assert_may_sleep() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let held = HELD.lock();
+        if let Some(lock) = held.held().iter().find(|lock| lock.spinlock) {
+            panic!("sleeping while holding the spinlock {}", lock.name);
+        }
+    });
+}
+
+/// Saves the held locks of the running thread to `old` and makes `new` the held locks of the
+/// thread that runs next. Interrupts must be disabled.
+pub fn switch(old: &mut HeldLocks, new: &HeldLocks) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut held = HELD.lock();
+    *old = *held;
+    *held = *new;
+}
@@ -0,0 +1,17 @@
+//! Synchronization primitives.
+//!
+//! `IrqSpinlock` is for data that interrupt handlers use, too. The other primitives put the
+//! waiting thread to sleep through a `WaitQueue` and can only be used by threads.
+
+pub use self::irq_spinlock::{IrqSpinlock, IrqSpinlockGuard};
+pub use self::wait_queue::WaitQueue;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::condvar::Condvar;
+
+pub mod lock_order;
+mod irq_spinlock;
+mod wait_queue;
+mod mutex;
+mod semaphore;
+mod condvar;
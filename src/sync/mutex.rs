@@ -0,0 +1,92 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::WaitQueue;
+use super::lock_order;
+
+/// A lock whose waiters sleep instead of spinning.
+///
+/// It can be held for a long time and while sleeping, but it can't be used in interrupt
+/// handlers.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    name: &'static str,
+    level: u8,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates a mutex that is not checked by the lock order debugging.
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex::with_level(value, "unnamed mutex", lock_order::UNORDERED)
+    }
+
+    /// Creates a mutex with a place in the lock order, see `lock_order`.
+    pub const fn with_level(value: T, name: &'static str, level: u8) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            name: name,
+            level: level,
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Sleeps until the mutex is free and acquires it.
+    pub fn lock(&self) -> MutexGuard<T> {
+        lock_order::acquire(self.name, self.level, false);
+        self.waiters.wait_until(|| !self.locked.swap(true, Ordering::Acquire));
+        MutexGuard { mutex: self }
+    }
+
+    /// Acquires the mutex if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            lock_order::acquire(self.name, self.level, false);
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        lock_order::release(self.name, self.level);
+        self.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// The mutex that this guard locks, used by `Condvar` to lock it again.
+    pub fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
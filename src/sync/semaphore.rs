@@ -0,0 +1,45 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+/// A counting semaphore. `acquire` sleeps while the count is zero.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sleeps until the count is positive and decrements it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Decrements the count if it is positive. Returns false if it is zero.
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::SeqCst);
+        while count > 0 {
+            let previous = self.count.compare_and_swap(count, count - 1, Ordering::SeqCst);
+            if previous == count {
+                return true;
+            }
+            count = previous;
+        }
+        false
+    }
+
+    /// Increments the count and wakes a waiting thread. It can be called by interrupt handlers.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,83 @@
+use collections::VecDeque;
+use interrupts;
+use task::{self, ThreadId};
+use super::IrqSpinlock;
+use super::lock_order;
+
+/// A queue of threads that sleep until some condition becomes true.
+///
+/// There is only one CPU, so a thread that checks its condition with interrupts disabled can't
+/// miss the wakeup of a thread that makes it true. Waking is allowed in interrupt handlers,
+/// waiting is not.
+pub struct WaitQueue {
+    /// Created by the first `wait`, so that `new` can be a const fn.
+    waiters: IrqSpinlock<Option<VecDeque<ThreadId>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: IrqSpinlock::new(None) }
+    }
+
+    /// Puts the running thread to sleep until it is woken. Interrupts must be disabled, and they
+    /// are disabled again when it returns.
+    pub fn wait(&self) {
+        assert!(!interrupts::enabled(), "WaitQueue::wait with interrupts enabled");
+        lock_order::assert_may_sleep();
+        {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_none() {
+                *waiters = Some(VecDeque::new());
+            }
+            waiters.as_mut().unwrap().push_back(task::current());
+        }
+        task::block_current();
+    }
+
+    /// Sleeps until `condition` returns true. The condition is checked with interrupts disabled
+    /// every time the thread is woken.
+    pub fn wait_until<F>(&self, mut condition: F)
+        where F: FnMut() -> bool
+    {
+        interrupts::without_interrupts(|| {
+            while !condition() {
+                self.wait();
+            }
+        })
+    }
+
+    /// Wakes the thread that waits longest. Returns false if no thread was waiting.
+    ///
+    /// A queued thread might not be blocked anymore, e.g. because it was woken for another
+    /// reason or has exited. It is skipped, so that the wakeup isn't lost.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let next = self.waiters.lock().as_mut().and_then(|waiters| waiters.pop_front());
+            match next {
+                Some(id) => {
+                    if task::wake(id) {
+                        return true;
+                    }
+                }
+                None => return false,
+            }
+        }
+    }
+
+    /// Wakes all waiting threads and returns the number of threads that were blocked.
+    ///
+    /// Only the threads that wait when it is called are woken, so that a woken thread that waits
+    /// again before this returns stays queued for the next wakeup. The queue isn't replaced,
+    /// because interrupt handlers must not allocate or free memory.
+    pub fn wake_all(&self) -> usize {
+        let waiting = self.waiters.lock().as_ref().map_or(0, |waiters| waiters.len());
+        let mut woken = 0;
+        for _ in 0..waiting {
+            let next = self.waiters.lock().as_mut().and_then(|waiters| waiters.pop_front());
+            if next.map_or(false, task::wake) {
+                woken += 1;
+            }
+        }
+        woken
+    }
+}
@@ -0,0 +1,378 @@
+//! The handlers of the system calls.
+//!
+//! The kernel accesses user buffers directly through `with_user_buffer`, which checks that they
+//! lie in user accessible areas of the running thread's address space and maps their pages
+//! first. The address space stays locked during the access, so that another thread can't unmap
+//! the buffer and the access can't fault.
+
+use alloc::arc::Arc;
+use arena::Arena;
+use collections::Vec;
+use core::{cmp, mem, ptr, slice, str};
+use boot_modules;
+use memory::{self, Backing, PageFaultError, Vma, PAGE_SIZE, USER_END};
+use keyboard;
+use process::{self, ExitStatus, File, SIGNAL_COUNT, SIGKILL, SIGSTOP, SIG_IGN};
+use task;
+use vga;
+use super::{Error, SyscallFrame, SyscallResult};
+
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// `waitpid` returns 0 instead of blocking if no matching child has exited.
+pub const WNOHANG: usize = 1 << 0;
+
+// The commands and flags of `fcntl`.
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const FD_CLOEXEC: usize = 1 << 0;
+
+/// Buffers are copied through the kernel stack in pieces of this size.
+const CHUNK_SIZE: usize = 256;
+
+/// The maximum size of the path, arguments and environment of `execve`, including the pointer
+/// arrays.
+const MAX_EXEC_SIZE: usize = 16 * PAGE_SIZE;
+
+/// `exit(status)`: ends the calling thread. The process exits with the low byte of `status`
+/// when its last thread exits.
+pub fn exit(frame: &mut SyscallFrame) -> SyscallResult {
+    process::exit(ExitStatus::Exited(frame.arg(0) as u8));
+}
+
+/// `write(fd, buffer, len)`: writes to the open file `fd`. Returns the number of bytes written.
+pub fn write(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, buffer, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    let file = try!(open_file(fd));
+    try!(check_user_buffer(buffer, len, false));
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let count = cmp::min(CHUNK_SIZE, len - offset);
+        try!(with_user_buffer(buffer + offset, count, false, |source| unsafe {
+            ptr::copy_nonoverlapping(source, chunk.as_mut_ptr(), count)
+        }));
+        match *file {
+            File::Console => vga::write_bytes(&chunk[..count]),
+        }
+        offset += count;
+    }
+    Ok(len)
+}
+
+/// `read(fd, buffer, len)`: reads from the open file `fd`. The console blocks until at least one
+/// character was typed. Returns the number of bytes read.
+pub fn read(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, buffer, len) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    let file = try!(open_file(fd));
+    try!(check_user_buffer(buffer, len, true));
+
+    let mut chunk = [0; CHUNK_SIZE];
+    let chunk = &mut chunk[..cmp::min(CHUNK_SIZE, len)];
+    let count = match *file {
+        File::Console => keyboard::read(chunk),
+    };
+    try!(with_user_buffer(buffer, count, true, |destination| unsafe {
+        ptr::copy_nonoverlapping(chunk.as_ptr(), destination, count)
+    }));
+    Ok(count)
+}
+
+/// `yield()`: gives the CPU to the next ready thread.
+pub fn yield_now(_frame: &mut SyscallFrame) -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+/// `sleep(millis)`: blocks the calling thread for at least `millis` milliseconds.
+pub fn sleep(frame: &mut SyscallFrame) -> SyscallResult {
+    task::sleep_millis(frame.arg(0) as u64);
+    Ok(0)
+}
+
+/// `mmap(address, len, protection)`: maps `len` bytes of zeroed memory with the `PROT_*`
+/// access rights, at the page aligned `address` or, if it is 0, at a free address. User memory
+/// is always readable. Returns the start of the mapping.
+pub fn mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let (address, len, protection) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    if len == 0 || len > USER_END || protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    let flags = memory::user_page_flags(protection & PROT_WRITE != 0,
+                                        protection & PROT_EXEC != 0);
+
+    let space = try!(task::current_space().ok_or(Error::BadAddress));
+    let mut space = space.lock();
+    if address == 0 {
+        let vma = try!(space.allocate_vma(len, flags, Backing::Anonymous));
+        return Ok(vma.start);
+    }
+
+    let size = page_align_up(len);
+    if address % PAGE_SIZE != 0 || address >= USER_END || size > USER_END - address {
+        return Err(Error::InvalidArgument);
+    }
+    try!(space.insert_vma(Vma::new(address, address + size, flags, Backing::Anonymous)));
+    Ok(address)
+}
+
+/// `munmap(address, len)`: removes a mapping. It must be removed as a whole, i.e. `address` and
+/// `len` must be the start and length of a mapping.
+pub fn munmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let (address, len) = (frame.arg(0), frame.arg(1));
+    let space = try!(task::current_space().ok_or(Error::BadAddress));
+    let mut space = space.lock();
+    let matches = match space.find_vma(address) {
+        Some(vma) => {
+            vma.start == address && len <= vma.size() && page_align_up(len) == vma.size()
+        }
+        None => false,
+    };
+    if !matches {
+        return Err(Error::InvalidArgument);
+    }
+    try!(space.unmap_vma(address));
+    Ok(0)
+}
+
+/// `getpid()`: returns the id of the calling process.
+pub fn getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::current())
+}
+
+/// `getppid()`: returns the id of the parent of the calling process, or 0 for init.
+pub fn getppid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::with_current(|process| process.parent))
+}
+
+/// `gettid()`: returns the id of the calling thread.
+pub fn gettid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(task::current())
+}
+
+/// `waitpid(pid, status, options)`: waits until the child `pid`, or any child if `pid` is -1,
+/// exits and reaps it. Its exit status is stored to the `u32` at `status`, unless that is 0.
+/// Returns the id of the child, or 0 if `options` contains `WNOHANG` and no matching child has
+/// exited yet.
+pub fn waitpid(frame: &mut SyscallFrame) -> SyscallResult {
+    let (pid, status_address, options) = (frame.arg(0) as isize, frame.arg(1), frame.arg(2));
+    // there are no process groups
+    let pid = match pid {
+        -1 => None,
+        pid if pid > 0 => Some(pid as usize),
+        _ => return Err(Error::InvalidArgument),
+    };
+    if options & !WNOHANG != 0 {
+        return Err(Error::InvalidArgument);
+    }
+    // checked before the child is reaped, so that its status isn't lost
+    if status_address != 0 {
+        try!(check_user_buffer(status_address, 4, true));
+    }
+
+    match try!(process::wait(pid, options & WNOHANG == 0)) {
+        Some((child, status)) => {
+            if status_address != 0 {
+                let status = status.to_wait_status();
+                try!(with_user_buffer(status_address, 4, true, |destination| unsafe {
+                    ptr::copy_nonoverlapping(&status as *const u32 as *const u8, destination, 4)
+                }));
+            }
+            Ok(child)
+        }
+        None => Ok(0),
+    }
+}
+
+/// `close(fd)`: closes the file descriptor `fd`.
+pub fn close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.arg(0);
+    match process::with_current(|process| process.files.close(fd)) {
+        Some(_) => Ok(0),
+        None => Err(Error::BadFileDescriptor),
+    }
+}
+
+/// `fcntl(fd, command, argument)`: `F_GETFD` returns the flags of the file descriptor `fd` and
+/// `F_SETFD` sets them to `argument`. The only flag is `FD_CLOEXEC`.
+pub fn fcntl(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, command, argument) = (frame.arg(0), frame.arg(1), frame.arg(2));
+    process::with_current(|process| {
+        let descriptor = match process.files.get_mut(fd) {
+            Some(descriptor) => descriptor,
+            None => return Err(Error::BadFileDescriptor),
+        };
+        match command {
+            F_GETFD if descriptor.close_on_exec => Ok(FD_CLOEXEC),
+            F_GETFD => Ok(0),
+            F_SETFD => {
+                descriptor.close_on_exec = argument & FD_CLOEXEC != 0;
+                Ok(0)
+            }
+            _ => Err(Error::InvalidArgument),
+        }
+    })
+}
+
+/// `fork()`: creates a copy of the calling process. Returns the id of the child in the parent
+/// and 0 in the child.
+pub fn fork(frame: &mut SyscallFrame) -> SyscallResult {
+    let mut child_frame = frame.clone();
+    child_frame.rax = 0;
+    Ok(try!(process::fork(child_frame)))
+}
+
+/// `execve(path, argv, envp)`: replaces the program of the calling process. `argv` and `envp`
+/// are null terminated arrays of pointers to null terminated strings, and `envp` may be null.
+/// There is no file system yet, so the program is the boot module that is named like the last
+/// component of `path`. It doesn't return on success.
+pub fn execve(frame: &mut SyscallFrame) -> SyscallResult {
+    // the strings are copied into one scratch buffer, which lives until the end of the call
+    let mut scratch = vec![0u8; MAX_EXEC_SIZE];
+    let mut arena = Arena::new(scratch.as_mut_ptr() as usize, scratch.len());
+    let mut size = 0;
+    let path = try!(copy_user_string(frame.arg(0), &mut arena, &mut size));
+    let argv = try!(copy_user_strings(frame.arg(1), &mut arena, &mut size));
+    let envp = try!(copy_user_strings(frame.arg(2), &mut arena, &mut size));
+
+    let name = match path.iter().rposition(|&byte| byte == b'/') {
+        Some(index) => &path[(index + 1)..],
+        None => path,
+    };
+    let module = try!(str::from_utf8(name)
+        .ok()
+        .and_then(boot_modules::find)
+        .ok_or(Error::NoSuchFile));
+
+    let image = try!(process::exec(module.data, &argv, &envp));
+    *frame = SyscallFrame::program_start(image.entry, image.stack_pointer);
+    Ok(0)
+}
+
+/// `signal(signal, handler)`: sets the handler of `signal` to `SIG_DFL`, `SIG_IGN` or the address
+/// of a function and returns the previous handler. The handlers are kept across `fork` and reset
+/// by `execve`, but signals can't be sent yet.
+pub fn signal(frame: &mut SyscallFrame) -> SyscallResult {
+    let (signal, handler) = (frame.arg(0), frame.arg(1));
+    if signal == 0 || signal >= SIGNAL_COUNT || signal == SIGKILL as usize ||
+       signal == SIGSTOP as usize {
+        return Err(Error::InvalidArgument);
+    }
+    if handler > SIG_IGN && handler >= USER_END {
+        return Err(Error::BadAddress);
+    }
+    Ok(process::with_current(|process| mem::replace(&mut process.signal_handlers[signal], handler)))
+}
+
+/// Returns the open file `fd` of the calling process.
+fn open_file(fd: usize) -> Result<Arc<File>, Error> {
+    let file = process::with_current(|process| {
+        process.files.get(fd).map(|descriptor| descriptor.file.clone())
+    });
+    file.ok_or(Error::BadFileDescriptor)
+}
+
+/// Checks that the user buffer `[address, address + len)` can be read, or written if `write` is
+/// set, e.g. before a call has side effects. The buffer must still be accessed through
+/// `with_user_buffer`.
+fn check_user_buffer(address: usize, len: usize, write: bool) -> Result<(), Error> {
+    if len == 0 {
+        return Ok(());
+    }
+    let space = try!(task::current_space().ok_or(Error::BadAddress));
+    let accessible = space.lock().is_user_accessible(address, len, write);
+    if accessible {
+        Ok(())
+    } else {
+        Err(Error::BadAddress)
+    }
+}
+
+/// Runs `f` with a pointer to the user buffer `[address, address + len)`, which can be read, or
+/// written if `write` is set. The pages of the buffer are mapped first and the address space
+/// stays locked while `f` runs, so `f` must not block or access other user memory.
+fn with_user_buffer<F, R>(address: usize, len: usize, write: bool, f: F) -> Result<R, Error>
+    where F: FnOnce(*mut u8) -> R
+{
+    if len == 0 {
+        return Ok(f(address as *mut u8));
+    }
+    let space = try!(task::current_space().ok_or(Error::BadAddress));
+    let mut space = space.lock();
+    match space.fault_in_user(address, len, write) {
+        Ok(()) => Ok(f(address as *mut u8)),
+        Err(PageFaultError::OutOfMemory) => Err(Error::OutOfMemory),
+        Err(_) => Err(Error::BadAddress),
+    }
+}
+
+/// Copies the null terminated string at `address` from user memory into `arena`, without the
+/// null byte. `size` is the total size of the copied strings, which must stay below
+/// `MAX_EXEC_SIZE`. The string lives as long as the memory of the arena.
+fn copy_user_string<'a>(address: usize,
+                        arena: &mut Arena,
+                        size: &mut usize)
+                        -> Result<&'a [u8], Error> {
+    // the string grows in place as the most recent allocation of the arena
+    let start = try!(arena.allocate(0, 1).ok_or(Error::ArgumentsTooLong));
+    let mut string_len = 0;
+    let mut address = address;
+    loop {
+        // the string might end before an inaccessible page, so the pages are checked one by one
+        let len = PAGE_SIZE - address % PAGE_SIZE;
+        let end = try!(try!(with_user_buffer(address, len, false, |source| {
+            let bytes = unsafe { slice::from_raw_parts(source as *const u8, len) };
+            let end = bytes.iter().position(|&byte| byte == 0);
+            let part = &bytes[..end.unwrap_or(len)];
+            *size += part.len() + 1;
+            if *size > MAX_EXEC_SIZE ||
+               !arena.resize_last(start, string_len, string_len + part.len()) {
+                return Err(Error::ArgumentsTooLong);
+            }
+            unsafe {
+                ptr::copy_nonoverlapping(part.as_ptr(),
+                                         start.offset(string_len as isize),
+                                         part.len())
+            };
+            string_len += part.len();
+            Ok(end)
+        })));
+        if end.is_some() {
+            return Ok(unsafe { slice::from_raw_parts(start, string_len) });
+        }
+        address += len;
+    }
+}
+
+/// Copies the strings of the null terminated pointer array at `address` from user memory into
+/// `arena`. A null `address` is an empty array. `size` is counted like in `copy_user_string`.
+fn copy_user_strings<'a>(address: usize,
+                         arena: &mut Arena,
+                         size: &mut usize)
+                         -> Result<Vec<&'a [u8]>, Error> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Ok(strings);
+    }
+    let mut address = address;
+    loop {
+        let mut pointer = [0; 8];
+        try!(with_user_buffer(address, 8, false, |source| unsafe {
+            ptr::copy_nonoverlapping(source as *const u8, pointer.as_mut_ptr(), 8)
+        }));
+        let pointer = pointer.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize);
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        *size += 8;
+        strings.push(try!(copy_user_string(pointer, arena, size)));
+        address += 8;
+    }
+}
+
+fn page_align_up(len: usize) -> usize {
+    (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+}
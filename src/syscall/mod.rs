@@ -0,0 +1,370 @@
+//! System calls.
+//!
+//! User programs enter the kernel with `syscall`, or with `int 0x80`, which is slower but easier
+//! to follow in a debugger. Like on Linux, the number of the system call is passed in `rax` and
+//! up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is returned in
+//! `rax`, where a negative value is an `Error`. Both entries clobber `rcx` and `r11`, all other
+//! registers are preserved.
+
+use core::intrinsics;
+use x86::irq;
+use x86::msr::{IA32_STAR, IA32_LSTAR, IA32_FMASK, wrmsr};
+use interrupts::gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use elf;
+use memory::VmaError;
+use process;
+
+mod calls;
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_READ: usize = 2;
+pub const SYS_YIELD: usize = 3;
+pub const SYS_SLEEP: usize = 4;
+pub const SYS_MMAP: usize = 5;
+pub const SYS_MUNMAP: usize = 6;
+pub const SYS_GETPID: usize = 7;
+pub const SYS_GETTID: usize = 8;
+pub const SYS_GETPPID: usize = 9;
+pub const SYS_WAITPID: usize = 10;
+pub const SYS_CLOSE: usize = 11;
+pub const SYS_FORK: usize = 12;
+pub const SYS_EXECVE: usize = 13;
+pub const SYS_SIGNAL: usize = 14;
+pub const SYS_FCNTL: usize = 15;
+
+/// The RFLAGS bits that are cleared on `syscall`: interrupts stay disabled until the entry is on
+/// the kernel stack, and the direction, trap and alignment check flags are reset.
+const SYSCALL_FLAGS_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 18;
+
+/// The errors of system calls. They are returned negated and have the numbers of the Linux
+/// error codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// `ENOENT`, there is no file with the given name.
+    NoSuchFile = 2,
+    /// `E2BIG`, the arguments of a program are too long.
+    ArgumentsTooLong = 7,
+    /// `ENOEXEC`, a file is not an executable that can be loaded.
+    ExecFormat = 8,
+    /// `EBADF`, the file descriptor is not open or not opened for the access.
+    BadFileDescriptor = 9,
+    /// `ECHILD`, the calling process has no child that matches.
+    NoChildProcess = 10,
+    /// `EAGAIN`, a resource is temporarily exhausted.
+    TryAgain = 11,
+    /// `ENOMEM`, there is no memory or address range left.
+    OutOfMemory = 12,
+    /// `EFAULT`, a buffer is not accessible by the calling program.
+    BadAddress = 14,
+    /// `EINVAL`
+    InvalidArgument = 22,
+    /// `ENOSYS`, there is no system call with this number.
+    NoSuchSyscall = 38,
+}
+
+impl From<VmaError> for Error {
+    fn from(error: VmaError) -> Error {
+        match error {
+            VmaError::Overlap | VmaError::NotFound => Error::InvalidArgument,
+            VmaError::NoSpace | VmaError::TooManyAreas => Error::OutOfMemory,
+        }
+    }
+}
+
+impl From<process::Error> for Error {
+    fn from(error: process::Error) -> Error {
+        match error {
+            process::Error::NoChildren => Error::NoChildProcess,
+            process::Error::NoFreePid => Error::TryAgain,
+            process::Error::Exec(elf::Error::ArgumentsTooLong) => Error::ArgumentsTooLong,
+            process::Error::Exec(elf::Error::OutOfMemory) => Error::OutOfMemory,
+            process::Error::Exec(_) => Error::ExecFormat,
+        }
+    }
+}
+
+pub type SyscallResult = Result<usize, Error>;
+
+/// The user registers, as saved by both entries. A system call returns to user mode with them,
+/// so a handler can change where the program continues.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    /// The system call number, and the result on return.
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    /// Returns a frame that starts a program at `entry` with the stack pointer `stack_pointer`,
+    /// interrupts enabled and the other registers cleared.
+    pub fn program_start(entry: usize, stack_pointer: usize) -> SyscallFrame {
+        SyscallFrame {
+            rax: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            r10: 0,
+            r8: 0,
+            r9: 0,
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            rbx: 0,
+            rbp: 0,
+            rip: entry as u64,
+            rflags: 0x202,
+            rsp: stack_pointer as u64,
+        }
+    }
+
+    /// Returns argument `index` of the system call, counting from 0.
+    pub fn arg(&self, index: usize) -> usize {
+        let arg = match index {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("system calls have at most six arguments"),
+        };
+        arg as usize
+    }
+}
+
+type Handler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// Returns the handler of system call `number`.
+fn handler(number: usize) -> Option<Handler> {
+    let handler: Handler = match number {
+        SYS_EXIT => calls::exit,
+        SYS_WRITE => calls::write,
+        SYS_READ => calls::read,
+        SYS_YIELD => calls::yield_now,
+        SYS_SLEEP => calls::sleep,
+        SYS_MMAP => calls::mmap,
+        SYS_MUNMAP => calls::munmap,
+        SYS_GETPID => calls::getpid,
+        SYS_GETTID => calls::gettid,
+        SYS_GETPPID => calls::getppid,
+        SYS_WAITPID => calls::waitpid,
+        SYS_CLOSE => calls::close,
+        SYS_FORK => calls::fork,
+        SYS_EXECVE => calls::execve,
+        SYS_SIGNAL => calls::signal,
+        SYS_FCNTL => calls::fcntl,
+        _ => return None,
+    };
+    Some(handler)
+}
+
+/// Points the `syscall` instruction to `syscall_entry`. `syscall` must have been enabled in
+/// `IA32_EFER`.
+pub fn init() {
+    assert_has_not_been_called!("syscall::init must be called only once");
+
+    // `syscall` loads CS and SS from bits 32..48, `sysret` loads SS from bits 48..64 plus 8 and
+    // CS from them plus 16
+    let star = ((USER_DATA_SELECTOR - 8) as u64) << 48 | (KERNEL_CODE_SELECTOR as u64) << 32;
+    unsafe {
+        wrmsr(IA32_STAR, star);
+        wrmsr(IA32_LSTAR, syscall_entry as u64);
+        wrmsr(IA32_FMASK, SYSCALL_FLAGS_MASK);
+    }
+}
+
+/// Runs the system call in `frame` with interrupts enabled and stores its result in `rax`.
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    unsafe { irq::enable() };
+    let result = match handler(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(Error::NoSuchSyscall),
+    };
+    frame.rax = match result {
+        Ok(value) => value as u64,
+        Err(error) => (-(error as i64)) as u64,
+    };
+    // the entries return to user mode on the kernel stack, which an interrupt must not use
+    unsafe { irq::disable() };
+}
+
+/// The user stack pointer between `syscall` and the switch to the kernel stack, where it is
+/// pushed. Interrupts are disabled in between, so it can't be overwritten.
+#[no_mangle]
+static mut SYSCALL_USER_STACK: usize = 0;
+
+/// The target of `syscall`. It is entered in ring 0 with interrupts disabled, but still on the
+/// user stack, with the user instruction pointer in `rcx` and the user RFLAGS in `r11`.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!("mov [SYSCALL_USER_STACK], rsp
+          mov rsp, [SYSCALL_KERNEL_STACK]
+
+          push qword ptr [SYSCALL_USER_STACK]
+          push r11
+          push rcx
+          push rbp
+          push rbx
+          push r12
+          push r13
+          push r14
+          push r15
+          push r9
+          push r8
+          push r10
+          push rdx
+          push rsi
+          push rdi
+          push rax
+
+          mov rdi, rsp
+          call $0
+          jmp $1"
+         :: "s"(dispatch as extern "C" fn(&mut SyscallFrame)),
+            "s"(return_to_user as unsafe extern "C" fn())
+         :: "volatile", "intel");
+    intrinsics::unreachable();
+}
+
+extern "C" {
+    /// The `iretq` instruction of `return_to_user`.
+    fn return_to_user_iretq();
+}
+
+/// Returns to user mode with the registers of the `SyscallFrame` that `rsp` points to, like at
+/// the end of a system call. Interrupts must be disabled.
+///
+/// `sysretq` faults in ring 0, but already on the user stack, if the instruction pointer isn't
+/// canonical. Such an instruction pointer, e.g. from a signal frame, is returned to with `iretq`
+/// instead, which faults on the kernel stack, see `is_user_return_fault`.
+#[naked]
+pub unsafe extern "C" fn return_to_user() {
+    asm!("mov rcx, [rsp + 13 * 8]
+          shr rcx, 47
+          jnz return_to_user_with_iretq
+
+          pop rax
+          pop rdi
+          pop rsi
+          pop rdx
+          pop r10
+          pop r8
+          pop r9
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbx
+          pop rbp
+          pop rcx
+          pop r11
+          pop rsp
+
+          sysretq
+
+      return_to_user_with_iretq:
+          pop rax
+          pop rdi
+          pop rsi
+          pop rdx
+          pop r10
+          pop r8
+          pop r9
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbx
+          pop rbp
+
+          push $0
+          push qword ptr [rsp + 3 * 8]
+          push qword ptr [rsp + 3 * 8]
+          push $1
+          push qword ptr [rsp + 4 * 8]
+          mov rcx, [rsp]
+          mov r11, [rsp + 2 * 8]
+
+          .global return_to_user_iretq
+      return_to_user_iretq:
+          iretq"
+         :: "i"(USER_DATA_SELECTOR as u64), "i"(USER_CODE_SELECTOR as u64)
+         :: "volatile", "intel");
+    intrinsics::unreachable();
+}
+
+/// Returns true if an exception at `instruction_pointer` was raised by `return_to_user` when it
+/// returned to a non-canonical instruction pointer. The exception is caused by the user thread,
+/// which can't continue.
+pub fn is_user_return_fault(instruction_pointer: u64) -> bool {
+    instruction_pointer == return_to_user_iretq as usize as u64
+}
+
+/// The handler of `int 0x80`. The CPU already switched to the kernel stack, so the entry copies
+/// the instruction pointer, flags and stack pointer from the interrupt frame into a
+/// `SyscallFrame`, and back before `iretq`. The CPU pushed five words on a 16-byte aligned
+/// stack, so the stack is realigned for the call.
+#[naked]
+pub unsafe extern "C" fn int80_entry() {
+    asm!("push qword ptr [rsp + 3 * 8]
+          push qword ptr [rsp + 3 * 8]
+          push qword ptr [rsp + 2 * 8]
+          push rbp
+          push rbx
+          push r12
+          push r13
+          push r14
+          push r15
+          push r9
+          push r8
+          push r10
+          push rdx
+          push rsi
+          push rdi
+          push rax
+
+          mov rdi, rsp
+          sub rsp, 8
+          call $0
+          add rsp, 8
+
+          pop rax
+          pop rdi
+          pop rsi
+          pop rdx
+          pop r10
+          pop r8
+          pop r9
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbx
+          pop rbp
+          pop rcx
+          mov [rsp + 2 * 8], rcx
+          pop rcx
+          mov [rsp + 3 * 8], rcx
+          pop rcx
+          mov [rsp + 3 * 8], rcx
+
+          iretq" :: "s"(dispatch as extern "C" fn(&mut SyscallFrame)) :: "volatile", "intel");
+    intrinsics::unreachable();
+}
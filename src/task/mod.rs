@@ -0,0 +1,530 @@
+//! Kernel threads.
+//!
+//! Every thread runs on its own kernel stack. A thread that isn't running has its callee-saved
+//! registers pushed on its stack by `switch_stack`, and only the stack pointer is kept in its
+//! `Thread`. A thread that was preempted by the timer additionally has the registers that
+//! `make_idt_entry!` saved on its stack, so it continues with `iretq` when it is switched back
+//! in.
+//!
+//! The boot thread (the one that runs `rust_main`) becomes the idle thread. It only runs if no
+//! other thread is ready. The order of the other threads is decided by a `Scheduler`.
+//!
+//! User threads have an address space, which is loaded while they run; kernel threads run in
+//! the kernel address space.
+
+use alloc::boxed::Box;
+use collections::{BTreeMap, Vec};
+use core::ptr;
+use spin::Mutex;
+use memory::{self, Stack};
+use interrupts;
+use interrupts::gdt;
+use sync::lock_order::{self, HeldLocks};
+use x86::irq;
+
+pub use self::scheduler::{Policy, Scheduler, SchedInfo, DEFAULT_POLICY, MAX_PRIORITY, MIN_NICE,
+                          MAX_NICE};
+pub use self::user::{UserStart, spawn_user, replace_space, leave_space, current_space,
+                     current_process, handle_user_page_fault};
+use self::user::UserContext;
+
+mod scheduler;
+mod user;
+
+pub type ThreadId = usize;
+
+/// The size of a thread stack in pages.
+const STACK_PAGES: usize = 4;
+
+/// The timer interrupt comes from the PIT, which runs at this frequency with its default divisor
+/// of 65536, i.e. about 18.2 ticks per second.
+const TIMER_FREQUENCY: u64 = 1_193_182;
+const TIMER_DIVISOR: u64 = 65_536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    /// Waiting for something, e.g. another thread to exit.
+    Blocked,
+    /// The thread has exited and waits to be joined.
+    Exited,
+}
+
+struct Thread {
+    state: State,
+    sched: SchedInfo,
+    /// The saved stack pointer while the thread isn't running.
+    rsp: usize,
+    /// `None` for the boot thread, which runs on the boot stack.
+    stack: Option<Stack>,
+    /// The function that is started by `thread_start`.
+    entry: Option<fn()>,
+    /// Threads that wait in `join` for this thread to exit.
+    joiners: Vec<ThreadId>,
+    /// The locks held while the thread isn't running, for the lock order debugging.
+    held_locks: HeldLocks,
+    /// `None` for kernel threads.
+    user: Option<UserContext>,
+}
+
+struct Threads {
+    /// The threads are boxed, so that `switch_stack` can store to their `rsp` after the lock
+    /// was released.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Orders the ready threads, except the idle thread.
+    scheduler: Box<Scheduler>,
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: ThreadId,
+    /// The number of timer ticks since `init`.
+    ticks: u64,
+    /// Threads in `sleep` and the tick they wake up at. It has room for all threads, so that the
+    /// timer interrupt doesn't allocate.
+    sleepers: Vec<(u64, ThreadId)>,
+}
+
+/// All threads. It is only locked with interrupts disabled, because the timer interrupt locks
+/// it, too.
+static THREADS: Mutex<Option<Threads>> = Mutex::new(None);
+
+impl Threads {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("no such thread")
+    }
+
+    /// Makes a blocked or new thread ready to run.
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).expect("no such thread");
+        thread.state = State::Ready;
+        if id != self.idle {
+            self.scheduler.enqueue(id, &mut thread.sched);
+        }
+    }
+
+    /// Makes the sleeping threads whose wakeup tick has come ready.
+    fn wake_sleepers(&mut self) {
+        let mut i = 0;
+        while i < self.sleepers.len() {
+            let (wakeup, id) = self.sleepers[i];
+            if wakeup > self.ticks {
+                i += 1;
+                continue;
+            }
+            self.sleepers.swap_remove(i);
+            // the thread might have been woken and exited in the meantime
+            if self.threads.get(&id).map(|thread| thread.state) == Some(State::Blocked) {
+                self.make_ready(id);
+            }
+        }
+    }
+
+    /// Chooses the next thread and marks it as running. Returns where to save the stack pointer
+    /// of the current thread and the stack pointer of the next one, or `None` if the current
+    /// thread keeps running.
+    fn switch_to_next(&mut self) -> Option<(*mut usize, usize)> {
+        let current = self.current;
+        let current_state = self.thread(current).state;
+        let next = match self.scheduler.pick_next() {
+            Some(next) => next,
+            None if current_state == State::Running => return None,
+            None => self.idle,
+        };
+        if next == current {
+            self.thread(current).state = State::Running;
+            return None;
+        }
+
+        if current_state == State::Running {
+            self.make_ready(current);
+        }
+        self.current = next;
+        let (next_rsp, next_locks) = {
+            let next = self.thread(next);
+            next.state = State::Running;
+            // interrupts in user mode switch to the kernel stack of the thread
+            if let Some(ref stack) = next.stack {
+                gdt::set_kernel_stack(stack.top());
+            }
+            let page_table = match next.user {
+                Some(ref user) => user.page_table,
+                None => memory::kernel_page_table(),
+            };
+            // the thread keeps its address space alive
+            unsafe { memory::load_page_table(page_table) };
+            (next.rsp, next.held_locks)
+        };
+        lock_order::switch(&mut self.thread(current).held_locks, &next_locks);
+        Some((&mut self.thread(current).rsp as *mut usize, next_rsp))
+    }
+}
+
+/// Turns the current flow of control into the boot thread. The other threads are scheduled
+/// according to `policy`.
+pub fn init(policy: Policy) {
+    assert_has_not_been_called!("task::init must be called only once");
+
+    let boot_thread = Box::new(Thread {
+        state: State::Running,
+        sched: SchedInfo::new(),
+        rsp: 0,
+        stack: None,
+        entry: None,
+        joiners: Vec::new(),
+        held_locks: HeldLocks::new(),
+        user: None,
+    });
+    let mut threads = BTreeMap::new();
+    threads.insert(0, boot_thread);
+
+    interrupts::without_interrupts(|| {
+        *THREADS.lock() = Some(Threads {
+            threads: threads,
+            scheduler: policy.create(),
+            current: 0,
+            idle: 0,
+            next_id: 1,
+            ticks: 0,
+            sleepers: Vec::new(),
+        });
+    });
+}
+
+/// Turns the boot thread into the idle thread, which halts the CPU until the next interrupt.
+pub fn idle() -> ! {
+    unsafe { irq::enable() };
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+/// Creates a thread that runs `entry` and makes it ready.
+pub fn spawn(entry: fn()) -> ThreadId {
+    spawn_thread(entry, None)
+}
+
+/// Creates a kernel thread, or a user thread if `user` is set.
+fn spawn_thread(entry: fn(), user: Option<UserContext>) -> ThreadId {
+    let stack = memory::alloc_stack(STACK_PAGES).expect("no memory for a thread stack");
+    let rsp = unsafe { initial_stack(&stack) };
+    let thread = Box::new(Thread {
+        state: State::Ready,
+        sched: SchedInfo::new(),
+        rsp: rsp,
+        stack: Some(stack),
+        entry: Some(entry),
+        joiners: Vec::new(),
+        held_locks: HeldLocks::new(),
+        user: user,
+    });
+
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let id = threads.next_id;
+        threads.next_id += 1;
+        threads.threads.insert(id, thread);
+        // the timer interrupt must not allocate, so the scheduler always has room for all threads
+        let len = threads.threads.len();
+        threads.scheduler.reserve(len);
+        threads.sleepers.reserve(len);
+        threads.make_ready(id);
+        id
+    })
+}
+
+/// Returns the id of the running thread.
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        THREADS.lock().as_ref().expect("task::init was not called").current
+    })
+}
+
+/// Gives the CPU to the next ready thread.
+pub fn yield_now() {
+    lock_order::assert_may_sleep();
+    interrupts::without_interrupts(schedule);
+}
+
+/// Blocks the running thread until `wake` is called for it. Interrupts must be disabled, so that
+/// the wakeup can't come before the thread is blocked. Use a `sync::WaitQueue` instead of calling
+/// this directly.
+pub fn block_current() {
+    assert!(!interrupts::enabled(), "block_current with interrupts enabled");
+    {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let current = threads.current;
+        assert!(current != threads.idle, "the idle thread can't block");
+        threads.thread(current).state = State::Blocked;
+    }
+    schedule();
+}
+
+/// Makes a thread that was blocked by `block_current` ready again. Returns false if it wasn't
+/// blocked. It can be called by interrupt handlers.
+pub fn wake(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let blocked = threads.threads.get(&id).map(|thread| thread.state) == Some(State::Blocked);
+        if blocked {
+            threads.make_ready(id);
+        }
+        blocked
+    })
+}
+
+/// Blocks the running thread for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    lock_order::assert_may_sleep();
+    interrupts::without_interrupts(|| {
+        let wakeup = {
+            let mut threads = THREADS.lock();
+            let threads = threads.as_mut().expect("task::init was not called");
+            threads.ticks + ticks
+        };
+        // a `wake` for another reason ends the sleep early, so it is started again
+        loop {
+            {
+                let mut threads = THREADS.lock();
+                let threads = threads.as_mut().unwrap();
+                if threads.ticks >= wakeup {
+                    return;
+                }
+                let current = threads.current;
+                assert!(current != threads.idle, "the idle thread can't sleep");
+                // the early wakeup left an entry behind
+                threads.sleepers.retain(|&(_, id)| id != current);
+                threads.sleepers.push((wakeup, current));
+                threads.thread(current).state = State::Blocked;
+            }
+            schedule();
+        }
+    })
+}
+
+/// Blocks the running thread for at least `millis` milliseconds.
+pub fn sleep_millis(millis: u64) {
+    let divisor = TIMER_DIVISOR * 1000;
+    sleep(millis.saturating_mul(TIMER_FREQUENCY).saturating_add(divisor - 1) / divisor);
+}
+
+/// Called by the timer interrupt. Accounts the tick to the running thread and preempts it if the
+/// scheduler says so.
+pub fn tick() {
+    let preempt = {
+        let mut threads = THREADS.lock();
+        let threads = match threads.as_mut() {
+            Some(threads) => threads,
+            None => return,
+        };
+        threads.ticks += 1;
+        threads.wake_sleepers();
+
+        let current = threads.current;
+        let idle = threads.idle;
+        let thread = threads.threads.get_mut(&current).expect("no such thread");
+        thread.sched.runtime += 1;
+        // the idle thread gives way to any ready thread
+        current == idle || threads.scheduler.tick(&mut thread.sched)
+    };
+    if preempt {
+        schedule();
+    }
+}
+
+/// Sets the priority of thread `id`, which is used by the priority scheduler. Returns false if
+/// there is no such thread.
+pub fn set_priority(id: ThreadId, priority: u8) -> bool {
+    assert!(priority <= MAX_PRIORITY, "priority must be at most {}", MAX_PRIORITY);
+    update_sched(id, |sched| sched.priority = priority)
+}
+
+/// Sets the nice level of thread `id`, which is used by the fair scheduler. Returns false if
+/// there is no such thread.
+pub fn set_nice(id: ThreadId, nice: i8) -> bool {
+    assert!(nice >= MIN_NICE && nice <= MAX_NICE,
+            "nice must be between {} and {}",
+            MIN_NICE,
+            MAX_NICE);
+    update_sched(id, |sched| sched.nice = nice)
+}
+
+/// Returns the scheduling parameters and the runtime of thread `id`.
+pub fn sched_info(id: ThreadId) -> Option<SchedInfo> {
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.lock();
+        let threads = threads.as_ref().expect("task::init was not called");
+        threads.threads.get(&id).map(|thread| thread.sched)
+    })
+}
+
+/// Changes the scheduling parameters of a thread. A ready thread is queued again, so that the
+/// scheduler sees the new parameters.
+fn update_sched<F>(id: ThreadId, f: F) -> bool
+    where F: FnOnce(&mut SchedInfo)
+{
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let requeue = match threads.threads.get_mut(&id) {
+            Some(thread) => {
+                f(&mut thread.sched);
+                thread.state == State::Ready
+            }
+            None => return false,
+        };
+        if requeue && threads.scheduler.remove(id) {
+            threads.make_ready(id);
+        }
+        true
+    })
+}
+
+/// Ends the current thread. Its stack is freed when it is joined.
+pub fn exit() -> ! {
+    unsafe { irq::disable() };
+    {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let current = threads.current;
+        assert!(current != threads.idle, "the idle thread can't exit");
+
+        let joiners = {
+            let thread = threads.thread(current);
+            thread.state = State::Exited;
+            ::core::mem::replace(&mut thread.joiners, Vec::new())
+        };
+        // a joiner might have been woken for another reason, or even have exited, since it
+        // was added
+        for joiner in joiners {
+            let blocked = threads.threads.get(&joiner).map(|thread| thread.state) ==
+                          Some(State::Blocked);
+            if blocked {
+                threads.make_ready(joiner);
+            }
+        }
+    }
+    schedule();
+    unreachable!("exited thread was scheduled");
+}
+
+enum Join {
+    NoSuchThread,
+    Exited(Box<Thread>),
+    Waiting,
+}
+
+/// Waits until thread `id` exits and frees it. Returns false if there is no such thread.
+pub fn join(id: ThreadId) -> bool {
+    loop {
+        let join = interrupts::without_interrupts(|| {
+            let join = {
+                let mut threads = THREADS.lock();
+                let threads = threads.as_mut().expect("task::init was not called");
+                let current = threads.current;
+                assert!(id != current, "a thread can't join itself");
+
+                match threads.threads.get(&id).map(|thread| thread.state) {
+                    None => Join::NoSuchThread,
+                    Some(State::Exited) => Join::Exited(threads.threads.remove(&id).unwrap()),
+                    Some(_) => {
+                        // a thread that was woken for another reason is still a joiner
+                        {
+                            let joiners = &mut threads.thread(id).joiners;
+                            if !joiners.contains(&current) {
+                                joiners.push(current);
+                            }
+                        }
+                        threads.thread(current).state = State::Blocked;
+                        Join::Waiting
+                    }
+                }
+            };
+            if let Join::Waiting = join {
+                schedule();
+            }
+            join
+        });
+
+        match join {
+            Join::NoSuchThread => return false,
+            // the stack is freed here, with interrupts enabled and without holding the lock
+            Join::Exited(thread) => {
+                drop(thread);
+                return true;
+            }
+            Join::Waiting => {}
+        }
+    }
+}
+
+/// Switches to the next thread. Interrupts must be disabled.
+fn schedule() {
+    let switch = {
+        let mut threads = THREADS.lock();
+        match threads.as_mut() {
+            Some(threads) => threads.switch_to_next(),
+            None => None,
+        }
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { switch_stack(old_rsp, new_rsp) };
+    }
+}
+
+/// Prepares the stack of a new thread, so that `switch_stack` returns to `thread_start`.
+/// Returns the initial stack pointer.
+unsafe fn initial_stack(stack: &Stack) -> usize {
+    // `thread_start` is entered as if it was called, i.e. with `rsp + 8` 16-byte aligned, and
+    // the stack top is page aligned
+    let frame: [usize; 9] = [
+        0x2, // rflags, interrupts disabled until `thread_start` enables them
+        0, 0, 0, 0, 0, // r15, r14, r13, r12, rbx
+        0, // rbp, ends the chain of frame pointers
+        thread_start as usize, // the return address of `switch_stack`
+        0, // the return address of `thread_start`, which never returns
+    ];
+    let rsp = stack.top() - frame.len() * 8;
+    ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut usize, frame.len());
+    rsp
+}
+
+/// The first function of every new thread. It is entered with interrupts disabled.
+extern "C" fn thread_start() -> ! {
+    let entry = {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().unwrap();
+        let current = threads.current;
+        threads.thread(current).entry.take().unwrap()
+    };
+    unsafe { irq::enable() };
+    entry();
+    exit();
+}
+
+/// Saves the callee-saved registers and RFLAGS on the current stack, stores the stack pointer to
+/// `old_rsp`, and restores the registers from the stack at `new_rsp`.
+#[naked]
+unsafe extern "C" fn switch_stack(_old_rsp: *mut usize, _new_rsp: usize) {
+    asm!("push rbp
+          push rbx
+          push r12
+          push r13
+          push r14
+          push r15
+          pushfq
+
+          mov [rdi], rsp
+          mov rsp, rsi
+
+          popfq
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbx
+          pop rbp
+          ret" :::: "volatile", "intel");
+}
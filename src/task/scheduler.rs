@@ -0,0 +1,400 @@
+//! Scheduling policies.
+//!
+//! A `Scheduler` only decides the order of the ready threads; the thread states and the
+//! switching are handled by the task module. The schedulers are called from the timer interrupt,
+//! so they must not allocate outside of `reserve`.
+
+use super::ThreadId;
+use alloc::boxed::Box;
+use collections::{Vec, VecDeque};
+
+pub const MAX_PRIORITY: u8 = 31;
+pub const DEFAULT_PRIORITY: u8 = 16;
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// The scheduling parameters and statistics of a thread.
+#[derive(Debug, Clone, Copy)]
+pub struct SchedInfo {
+    /// Used by `PriorityScheduler`, higher runs first.
+    pub priority: u8,
+    /// Used by `FairScheduler`, lower gets more CPU time.
+    pub nice: i8,
+    /// The number of timer ticks the thread was running.
+    pub runtime: u64,
+    /// The weighted runtime of `FairScheduler`.
+    vruntime: u64,
+}
+
+impl SchedInfo {
+    pub fn new() -> SchedInfo {
+        SchedInfo {
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+            runtime: 0,
+            vruntime: 0,
+        }
+    }
+}
+
+/// The name of the policy that is used unless the `sched` parameter selects another one, see
+/// `Policy::from_name`.
+#[cfg(not(any(feature = "sched_priority", feature = "sched_fair")))]
+pub const DEFAULT_POLICY: &'static str = "rr";
+#[cfg(all(feature = "sched_priority", not(feature = "sched_fair")))]
+pub const DEFAULT_POLICY: &'static str = "priority";
+#[cfg(feature = "sched_fair")]
+pub const DEFAULT_POLICY: &'static str = "fair";
+
+/// The available scheduling policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    Priority,
+    Fair,
+}
+
+impl Policy {
+    /// Returns the policy with the given name: `rr`, `priority` or `fair`.
+    pub fn from_name(name: &str) -> Option<Policy> {
+        match name {
+            "rr" => Some(Policy::RoundRobin),
+            "priority" => Some(Policy::Priority),
+            "fair" => Some(Policy::Fair),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Box<Scheduler> {
+        match self {
+            Policy::RoundRobin => Box::new(RoundRobinScheduler::new()),
+            Policy::Priority => Box::new(PriorityScheduler::new()),
+            Policy::Fair => Box::new(FairScheduler::new()),
+        }
+    }
+}
+
+pub trait Scheduler: Send {
+    /// Makes room for `threads` ready threads, so that `enqueue` doesn't allocate.
+    fn reserve(&mut self, threads: usize);
+
+    /// Adds a thread that became ready.
+    fn enqueue(&mut self, id: ThreadId, info: &mut SchedInfo);
+
+    /// Removes a ready thread, e.g. because its parameters change. Returns false if it wasn't
+    /// queued.
+    fn remove(&mut self, id: ThreadId) -> bool;
+
+    /// Removes and returns the thread that runs next.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// Called on every timer tick with the running thread, after its runtime was increased.
+    /// Returns true if it should be preempted.
+    fn tick(&mut self, info: &mut SchedInfo) -> bool;
+}
+
+/// Runs the ready threads in turn, one tick each.
+pub struct RoundRobinScheduler {
+    ready: VecDeque<ThreadId>,
+}
+
+impl RoundRobinScheduler {
+    pub fn new() -> RoundRobinScheduler {
+        RoundRobinScheduler { ready: VecDeque::new() }
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn reserve(&mut self, threads: usize) {
+        let additional = threads.saturating_sub(self.ready.len());
+        self.ready.reserve(additional);
+    }
+
+    fn enqueue(&mut self, id: ThreadId, _info: &mut SchedInfo) {
+        self.ready.push_back(id);
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool {
+        match self.ready.iter().position(|&ready| ready == id) {
+            Some(index) => {
+                self.ready.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.ready.pop_front()
+    }
+
+    fn tick(&mut self, _info: &mut SchedInfo) -> bool {
+        true
+    }
+}
+
+/// Threads waiting this many ticks gain one priority level, so that low priority threads don't
+/// starve.
+const AGING_TICKS: u64 = 8;
+
+/// The number of ticks a thread runs before threads of the same priority get their turn.
+const TIME_SLICE: u64 = 4;
+
+struct PriorityEntry {
+    id: ThreadId,
+    priority: u8,
+    /// The tick at which the thread was enqueued.
+    since: u64,
+}
+
+/// Runs the thread with the highest priority. The priority of waiting threads is raised over
+/// time (aging).
+pub struct PriorityScheduler {
+    ready: Vec<PriorityEntry>,
+    /// The number of ticks so far.
+    now: u64,
+    /// The ticks of the running thread in its current time slice.
+    slice: u64,
+}
+
+impl PriorityScheduler {
+    pub fn new() -> PriorityScheduler {
+        PriorityScheduler {
+            ready: Vec::new(),
+            now: 0,
+            slice: 0,
+        }
+    }
+
+    fn effective_priority(&self, entry: &PriorityEntry) -> u64 {
+        entry.priority as u64 + (self.now - entry.since) / AGING_TICKS
+    }
+
+    /// Returns the index of the entry with the highest effective priority, the longest waiting
+    /// one if there are several.
+    fn best(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (index, entry) in self.ready.iter().enumerate() {
+            let better = match best {
+                None => true,
+                Some(best) => {
+                    let best = &self.ready[best];
+                    let (priority, best_priority) = (self.effective_priority(entry),
+                                                     self.effective_priority(best));
+                    priority > best_priority ||
+                    (priority == best_priority && entry.since < best.since)
+                }
+            };
+            if better {
+                best = Some(index);
+            }
+        }
+        best
+    }
+}
+
+impl Scheduler for PriorityScheduler {
+    fn reserve(&mut self, threads: usize) {
+        let additional = threads.saturating_sub(self.ready.len());
+        self.ready.reserve(additional);
+    }
+
+    fn enqueue(&mut self, id: ThreadId, info: &mut SchedInfo) {
+        self.ready.push(PriorityEntry {
+            id: id,
+            priority: info.priority,
+            since: self.now,
+        });
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool {
+        match self.ready.iter().position(|entry| entry.id == id) {
+            Some(index) => {
+                self.ready.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.best().map(|index| {
+            self.slice = 0;
+            self.ready.swap_remove(index).id
+        })
+    }
+
+    fn tick(&mut self, info: &mut SchedInfo) -> bool {
+        self.now += 1;
+        self.slice += 1;
+        let best = match self.best() {
+            Some(index) => self.effective_priority(&self.ready[index]),
+            None => return false,
+        };
+        let running = info.priority as u64;
+        best > running || (best == running && self.slice >= TIME_SLICE)
+    }
+}
+
+/// The weight of nice level 0.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// The weights of the nice levels -20 to 19. Every level gets about 25% less CPU time than the
+/// one below (the table of Linux' CFS).
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// The virtual runtime of one tick at nice level 0.
+const VRUNTIME_PER_TICK: u64 = 1_000_000;
+
+/// A running thread is only preempted if it is ahead of a ready thread by more than this.
+const GRANULARITY: u64 = VRUNTIME_PER_TICK;
+
+/// Shares the CPU by weight: every thread accumulates virtual runtime inversely proportional to
+/// its weight, and the thread with the least virtual runtime runs next (like Linux' CFS).
+pub struct FairScheduler {
+    ready: Vec<(ThreadId, u64)>,
+    /// The smallest virtual runtime of the ready and running threads. New and woken threads
+    /// start here, so that they can't claim the time they were not ready.
+    min_vruntime: u64,
+}
+
+impl FairScheduler {
+    pub fn new() -> FairScheduler {
+        FairScheduler {
+            ready: Vec::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    fn min_index(&self) -> Option<usize> {
+        self.ready
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &(_, vruntime))| vruntime)
+            .map(|(index, _)| index)
+    }
+}
+
+impl Scheduler for FairScheduler {
+    fn reserve(&mut self, threads: usize) {
+        let additional = threads.saturating_sub(self.ready.len());
+        self.ready.reserve(additional);
+    }
+
+    fn enqueue(&mut self, id: ThreadId, info: &mut SchedInfo) {
+        if info.vruntime < self.min_vruntime {
+            info.vruntime = self.min_vruntime;
+        }
+        self.ready.push((id, info.vruntime));
+    }
+
+    fn remove(&mut self, id: ThreadId) -> bool {
+        match self.ready.iter().position(|&(ready, _)| ready == id) {
+            Some(index) => {
+                self.ready.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.min_index().map(|index| {
+            let (id, vruntime) = self.ready.swap_remove(index);
+            if vruntime > self.min_vruntime {
+                self.min_vruntime = vruntime;
+            }
+            id
+        })
+    }
+
+    fn tick(&mut self, info: &mut SchedInfo) -> bool {
+        let weight = NICE_WEIGHTS[(info.nice - MIN_NICE) as usize];
+        info.vruntime += VRUNTIME_PER_TICK * NICE_0_WEIGHT / weight;
+        match self.min_index() {
+            Some(index) => info.vruntime > self.ready[index].1 + GRANULARITY,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, Scheduler, SchedInfo, PriorityScheduler, FairScheduler, DEFAULT_POLICY,
+                TIME_SLICE};
+    use collections::Vec;
+
+    /// Runs the threads for `ticks` timer ticks like the task module does: thread 0 is running at
+    /// the start and the others are ready. Returns the threads that ran in the ticks.
+    fn run<S: Scheduler>(scheduler: &mut S, infos: &mut [SchedInfo], ticks: usize) -> Vec<usize> {
+        for id in 1..infos.len() {
+            scheduler.enqueue(id, &mut infos[id]);
+        }
+        let mut current = 0;
+        let mut schedule = Vec::new();
+        for _ in 0..ticks {
+            schedule.push(current);
+            infos[current].runtime += 1;
+            if scheduler.tick(&mut infos[current]) {
+                scheduler.enqueue(current, &mut infos[current]);
+                current = scheduler.pick_next().unwrap();
+            }
+        }
+        schedule
+    }
+
+    fn priority(priority: u8) -> SchedInfo {
+        SchedInfo { priority: priority, ..SchedInfo::new() }
+    }
+
+    fn nice(nice: i8) -> SchedInfo {
+        SchedInfo { nice: nice, ..SchedInfo::new() }
+    }
+
+    #[test]
+    fn aging_lets_low_priorities_run() {
+        let mut infos = [priority(20), priority(10)];
+        run(&mut PriorityScheduler::new(), &mut infos, 100);
+        assert!(infos[1].runtime > 0);
+        assert!(infos[0].runtime > infos[1].runtime);
+    }
+
+    #[test]
+    fn equal_priorities_rotate_after_a_time_slice() {
+        let mut infos = [priority(16), priority(16), priority(16)];
+        let schedule = run(&mut PriorityScheduler::new(), &mut infos, 3 * TIME_SLICE as usize);
+        for (slice, threads) in schedule.chunks(TIME_SLICE as usize).enumerate() {
+            assert!(threads.iter().all(|&id| id == threads[0]));
+            assert!(schedule[..slice * TIME_SLICE as usize].iter().all(|&id| id != threads[0]));
+        }
+        assert!(infos.iter().all(|info| info.runtime == TIME_SLICE));
+    }
+
+    #[test]
+    fn lower_nice_levels_get_more_time() {
+        // the weights of nice -5 and 0 are 3121 and 1024
+        let mut infos = [nice(-5), nice(0)];
+        run(&mut FairScheduler::new(), &mut infos, 400);
+        let (high, low) = (infos[0].runtime, infos[1].runtime);
+        assert!(2 * high > 5 * low && 2 * high < 7 * low,
+                "nice -5 ran {} ticks and nice 0 {} ticks",
+                high,
+                low);
+    }
+
+    #[test]
+    fn the_default_policy_can_be_parsed() {
+        assert!(Policy::from_name(DEFAULT_POLICY).is_some());
+        assert_eq!(Policy::from_name("rr"), Some(Policy::RoundRobin));
+        assert_eq!(Policy::from_name("cfs"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! Threads that run in ring 3.
+//!
+//! A user thread starts like a kernel thread, but `user_thread_start` immediately leaves the
+//! kernel, with `iretq` at the entry point of a program or with `sysretq` in the child of
+//! `fork`. While it runs, its address space is loaded and the TSS points to its kernel stack, so
+//! interrupts and exceptions in user mode continue on that stack.
+
+use alloc::arc::Arc;
+use core::mem;
+use spin::Mutex;
+use x86::irq;
+use memory::{self, AddressSpace, PageFaultError};
+use interrupts;
+use interrupts::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use process::Pid;
+use syscall::{self, SyscallFrame};
+use super::{THREADS, ThreadId};
+
+/// The user part of a thread.
+pub struct UserContext {
+    /// The process that the thread belongs to.
+    pub process: Pid,
+    /// Shared by all threads of a process. The page fault handler only tries to lock it.
+    pub space: Arc<Mutex<AddressSpace>>,
+    /// The physical address of the P4 table of `space`, which is loaded when the thread runs.
+    pub page_table: usize,
+    /// Taken by `user_thread_start`.
+    start: Option<UserStart>,
+}
+
+/// How a user thread enters user mode for the first time.
+pub enum UserStart {
+    /// At the entry point of a program with the stack pointer `stack_top` and cleared registers.
+    Entry { entry: usize, stack_top: usize },
+    /// With the registers of a system call, like the child of `fork`.
+    Resume(SyscallFrame),
+}
+
+/// Creates a thread of `process` that enters user mode as `start` says, in the address space
+/// `space`.
+pub fn spawn_user(process: Pid, space: Arc<Mutex<AddressSpace>>, start: UserStart) -> ThreadId {
+    let context = UserContext {
+        process: process,
+        page_table: space.lock().page_table_address(),
+        space: space,
+        start: Some(start),
+    };
+    super::spawn_thread(user_thread_start, Some(context))
+}
+
+/// Returns the address space of the running thread, or `None` for kernel threads.
+pub fn current_space() -> Option<Arc<Mutex<AddressSpace>>> {
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.lock();
+        let threads = threads.as_ref().expect("task::init was not called");
+        threads.threads[&threads.current].user.as_ref().map(|user| user.space.clone())
+    })
+}
+
+/// Loads `space` and makes it the address space of the running thread. Returns the previous
+/// address space, which isn't loaded anymore.
+pub fn replace_space(space: Arc<Mutex<AddressSpace>>) -> Arc<Mutex<AddressSpace>> {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let current = threads.current;
+        let user = threads.thread(current).user.as_mut().expect("not a user thread");
+        user.page_table = space.lock().page_table_address();
+        unsafe { memory::load_page_table(user.page_table) };
+        mem::replace(&mut user.space, space)
+    })
+}
+
+/// Switches the running thread to the kernel page table, so that the address space of its process
+/// can be unmapped when the process exits. The thread must not return to user mode afterwards.
+pub fn leave_space() {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().expect("task::init was not called");
+        let current = threads.current;
+        let user = threads.thread(current).user.as_mut().expect("not a user thread");
+        user.page_table = memory::kernel_page_table();
+        unsafe { memory::load_page_table(user.page_table) };
+    })
+}
+
+/// Returns the process of the running thread, or `None` for kernel threads.
+pub fn current_process() -> Option<Pid> {
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.lock();
+        let threads = threads.as_ref().expect("task::init was not called");
+        threads.threads[&threads.current].user.as_ref().map(|user| user.process)
+    })
+}
+
+/// Handles a page fault at a lower half address in the address space of the running thread.
+///
+/// Another thread of the process may hold the address space, e.g. in `mmap`, so the handler
+/// waits for it. Page faults are handled with interrupts disabled, so they are enabled while
+/// waiting if `interrupts_enabled`, i.e. if they were enabled in the faulting code. Kernel code
+/// must not touch user memory while it holds the address space.
+pub fn handle_user_page_fault(address: usize,
+                              error_code: u64,
+                              interrupts_enabled: bool)
+                              -> Result<(), PageFaultError> {
+    let shared_space = {
+        // the threads are only locked with interrupts disabled, i.e. never by another thread
+        // while this one runs
+        let threads = match THREADS.try_lock() {
+            Some(threads) => threads,
+            None => return Err(PageFaultError::Locked),
+        };
+        let threads = threads.as_ref().expect("task::init was not called");
+        match threads.threads[&threads.current].user {
+            Some(ref user) => user.space.clone(),
+            None => return Err(PageFaultError::SegmentationFault),
+        }
+    };
+    let mut space = match shared_space.try_lock() {
+        Some(space) => space,
+        None if interrupts_enabled => {
+            unsafe { irq::enable() };
+            let space = shared_space.lock();
+            unsafe { irq::disable() };
+            space
+        }
+        None => return Err(PageFaultError::Locked),
+    };
+    memory::handle_user_page_fault(&mut space, address, error_code)
+}
+
+/// The entry function of every user thread. It runs in the kernel, with the address space of
+/// the thread already loaded.
+fn user_thread_start() {
+    let start = interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let threads = threads.as_mut().unwrap();
+        let current = threads.current;
+        let user = threads.thread(current).user.as_mut().expect("not a user thread");
+        user.start.take().expect("user thread was started twice")
+    });
+    match start {
+        UserStart::Entry { entry, stack_top } => unsafe { enter_user_mode(entry, stack_top) },
+        UserStart::Resume(frame) => unsafe { resume_user_mode(&frame) },
+    }
+}
+
+/// Jumps to `entry` in ring 3 with the stack pointer `stack_top` and interrupts enabled. The
+/// general purpose registers are cleared, so that no kernel data leaks to user mode.
+unsafe fn enter_user_mode(entry: usize, stack_top: usize) -> ! {
+    // iretq pops the instruction pointer, code segment, flags, stack pointer and stack segment
+    asm!("push $0
+          push $1
+          push $2
+          push $3
+          push $4
+
+          xor rax, rax
+          xor rbx, rbx
+          xor rcx, rcx
+          xor rdx, rdx
+          xor rsi, rsi
+          xor rdi, rdi
+          xor rbp, rbp
+          xor r8, r8
+          xor r9, r9
+          xor r10, r10
+          xor r11, r11
+          xor r12, r12
+          xor r13, r13
+          xor r14, r14
+          xor r15, r15
+
+          iretq"
+         :: "r"(USER_DATA_SELECTOR as u64), "r"(stack_top), "r"(0x202u64),
+            "r"(USER_CODE_SELECTOR as u64), "r"(entry)
+         :: "volatile", "intel");
+    unreachable!();
+}
+
+/// Returns to user mode with the registers in `frame`, like the end of `syscall_entry`. `rcx`
+/// and `r11` are clobbered, as after every system call.
+unsafe fn resume_user_mode(frame: *const SyscallFrame) -> ! {
+    // the registers are popped off the frame, so interrupts must not use the stack
+    asm!("cli
+          mov rsp, $0
+          jmp $1"
+         :: "r"(frame), "s"(syscall::return_to_user as unsafe extern "C" fn())
+         :: "volatile", "intel");
+    unreachable!();
+}
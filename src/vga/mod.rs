@@ -3,10 +3,12 @@ mod cursor;
 extern crate spin;
 extern crate x86;
 
-use spin::Mutex;
+use sync::IrqSpinlock;
+use sync::lock_order;
 use core::fmt;
 use core;
 
+const VGA_BUFFER: usize = ::memory::KERNEL_OFFSET + 0xb8000;
 const CONSOLE_COLS: isize = 80;
 const CONSOLE_ROWS: isize = 25;
 
@@ -21,6 +23,15 @@ pub fn clear_console() {
 	b.flush();
 }
 
+// writes raw bytes, e.g. the output of user programs, which doesn't have to be UTF-8
+pub fn write_bytes(bytes: &[u8]) {
+	let mut b = BUFFER.lock();
+	for &byte in bytes {
+		b.write_byte(byte, DEFAULT_COLOR);
+	}
+	b.flush();
+}
+
 #[allow(dead_code)]
 #[repr(u8)]
 pub enum Color {
@@ -62,13 +73,14 @@ struct VgaCell {
 	color: ColorCode,
 }
 
-pub static BUFFER: Mutex<VgaBuffer> = Mutex::new(VgaBuffer {
+// also used by interrupt handlers, e.g. to echo key presses
+pub static BUFFER: IrqSpinlock<VgaBuffer> = IrqSpinlock::with_level(VgaBuffer {
 	buffer: [VgaCell {
 		character: b' ',
 		color: DEFAULT_COLOR,
 	}; (CONSOLE_ROWS * CONSOLE_COLS) as usize],
 	position: 0,
-});
+}, "vga::BUFFER", lock_order::CONSOLE);
 
 pub struct VgaBuffer {
 	buffer: [VgaCell; (CONSOLE_ROWS * CONSOLE_COLS) as usize],
@@ -79,7 +91,7 @@ impl VgaBuffer {
 
 	pub fn flush(&self) {
 		unsafe {
-			let vga = 0xb8000 as *mut u8;
+			let vga = VGA_BUFFER as *mut u8;
 			let length = self.buffer.len() * 2;
 			let buffer = self.buffer.as_ptr() as *const u8;
 			core::ptr::copy_nonoverlapping(buffer, vga, length);
@@ -165,6 +177,17 @@ macro_rules! kprintln {
 	($fmt:expr, $($arg:tt)*) => (kprint!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Prints a line like `kprintln` if the `log` command line parameter enables messages of the
+/// given level, e.g. `klog!(Debug, "free frames: {}", count)`.
+#[macro_export]
+macro_rules! klog {
+	($level:ident, $($arg:tt)*) => ({
+		if $crate::cmdline::log_enabled($crate::cmdline::LogLevel::$level) {
+			kprintln!($($arg)*);
+		}
+	});
+}
+
 #[macro_export]
 macro_rules! kprint {
 	($($arg:tt)*) => ({